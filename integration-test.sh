@@ -1,6 +1,6 @@
 #!/bin/sh
 
-cargo test
+MILKMAN_LIVE_TESTS=1 cargo test
 
 export INFURA_API_KEY=e74132f416d346308763252779d7df22
 # export MILKMAN_ADDRESS=0x0045215a527aacCa0b209c63c1409B8f65E48040
@@ -0,0 +1,155 @@
+use ethers::types::{Address, H256};
+use ethers::utils::keccak256;
+use serde_json::{json, Map, Value};
+
+use crate::configuration::Configuration;
+
+/// The version of CoW's appData schema that documents follow.
+const SCHEMA_VERSION: &str = "1.1.0";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderClass {
+    Market,
+    Limit,
+}
+
+impl OrderClass {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Market => "market",
+            Self::Limit => "limit",
+        }
+    }
+}
+
+/// What goes into an appData document.
+struct Document<'a> {
+    app_code: &'a str,
+    instance: Option<&'a str>,
+    referrer: Option<Address>,
+    partner_fee: Option<(u16, Address)>, // in bps, and who gets it
+    order_class: OrderClass,
+    slippage_bps: Option<u16>,
+}
+
+impl Document<'_> {
+    fn to_json(&self) -> String {
+        let mut metadata = Map::new();
+        metadata.insert(
+            "orderClass".to_string(),
+            json!({ "orderClass": self.order_class.as_str() }),
+        );
+        metadata.insert(
+            "utm".to_string(),
+            json!({
+                "utmSource": self.app_code,
+                "utmContent": env!("CARGO_PKG_VERSION"),
+            }),
+        );
+        if let Some(slippage_bps) = self.slippage_bps {
+            metadata.insert("quote".to_string(), json!({ "slippageBips": slippage_bps }));
+        }
+        if let Some(referrer) = self.referrer {
+            metadata.insert("referrer".to_string(), json!({ "address": referrer }));
+        }
+        if let Some((bps, recipient)) = self.partner_fee {
+            metadata.insert(
+                "partnerFee".to_string(),
+                json!({ "bps": bps, "recipient": recipient }),
+            );
+        }
+
+        let mut document = Map::new();
+        document.insert("appCode".to_string(), json!(self.app_code));
+        if let Some(instance) = self.instance {
+            document.insert("environment".to_string(), json!(instance));
+        }
+        document.insert("metadata".to_string(), Value::Object(metadata));
+        document.insert("version".to_string(), json!(SCHEMA_VERSION));
+
+        // `serde_json` sorts keys, so equal documents always serialize the same
+        Value::Object(document).to_string()
+    }
+}
+
+/// An appData document, which orders commit to by its keccak hash. The
+/// orderbook only knows what a hash stands for once the document is uploaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppData {
+    pub document: String,
+    pub hash: H256,
+}
+
+impl AppData {
+    /// The document for an order of `order_class`, with the slippage tolerance
+    /// it was posted with, if any.
+    pub fn new(config: &Configuration, order_class: OrderClass, slippage_bps: Option<u16>) -> Self {
+        let partner_fee = config.partner_fee_bps.zip(config.partner_fee_recipient);
+        let document = Document {
+            app_code: &config.app_code,
+            instance: config.bot_instance.as_deref(),
+            referrer: config.referrer_address,
+            partner_fee,
+            order_class,
+            slippage_bps,
+        }
+        .to_json();
+        let hash = H256(keccak256(&document));
+        Self { document, hash }
+    }
+
+    /// The hash as orderbook requests take it, `0x`-prefixed hex.
+    pub fn hash_hex(&self) -> String {
+        format!("{:#x}", self.hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_document() {
+        let market = Document {
+            app_code: "milkman-bot",
+            instance: None,
+            referrer: None,
+            partner_fee: None,
+            order_class: OrderClass::Market,
+            slippage_bps: Some(50),
+        };
+        let parsed: Value = serde_json::from_str(&market.to_json()).unwrap();
+        assert_eq!(parsed["appCode"], "milkman-bot");
+        assert_eq!(parsed["version"], SCHEMA_VERSION);
+        assert_eq!(parsed["metadata"]["orderClass"]["orderClass"], "market");
+        assert_eq!(parsed["metadata"]["quote"]["slippageBips"], 50);
+        assert_eq!(parsed["metadata"]["utm"]["utmSource"], "milkman-bot");
+        assert!(parsed.get("environment").is_none());
+        assert!(parsed["metadata"].get("referrer").is_none());
+        assert!(market
+            .to_json()
+            .starts_with(r#"{"appCode":"milkman-bot","metadata":{"#));
+
+        let dai: Address = "0x6B175474E89094C44Da98b954EedeAC495271d0F"
+            .parse()
+            .unwrap();
+        let limit = Document {
+            app_code: "treasury-bot",
+            instance: Some("prod-1"),
+            referrer: Some(dai),
+            partner_fee: Some((10, dai)),
+            order_class: OrderClass::Limit,
+            slippage_bps: None,
+        };
+        let parsed: Value = serde_json::from_str(&limit.to_json()).unwrap();
+        assert_eq!(parsed["environment"], "prod-1");
+        assert_eq!(parsed["metadata"]["utm"]["utmSource"], "treasury-bot");
+        assert_eq!(parsed["metadata"]["orderClass"]["orderClass"], "limit");
+        assert!(parsed["metadata"].get("quote").is_none());
+        assert_eq!(
+            parsed["metadata"]["referrer"]["address"],
+            "0x6b175474e89094c44da98b954eedeac495271d0f"
+        );
+        assert_eq!(parsed["metadata"]["partnerFee"]["bps"], 10);
+    }
+}
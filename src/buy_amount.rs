@@ -0,0 +1,237 @@
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::abi::{self, ParamType, Token};
+use ethers::types::{Bytes, U256};
+use serde::Deserialize;
+use std::fmt;
+use std::future::Future;
+use std::str::FromStr;
+
+/// How to set an order's buy amount. `Slippage` takes the slippage tolerance
+/// off the quoted amount. The other strategies first search, with `eth_call`s
+/// to the swap's price checker, for the smallest buy amount it accepts, and
+/// never go below it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BuyAmountStrategy {
+    #[default]
+    Slippage,
+    CheckerMinimum,  // the smallest amount the price checker accepts
+    CheckerMidpoint, // halfway between that and the quoted amount
+    CheckerBounded,  // like `Slippage`, but at least the smallest amount the price checker accepts
+}
+
+impl BuyAmountStrategy {
+    pub fn uses_checker(self) -> bool {
+        self != Self::Slippage
+    }
+
+    /// Whether buy amounts come from the slippage tolerance, so that orders
+    /// have one.
+    pub fn has_slippage_tolerance(self) -> bool {
+        matches!(self, Self::Slippage | Self::CheckerBounded)
+    }
+
+    /// The buy amount for an order, given the quoted amount, what it comes to
+    /// after slippage, and the smallest amount the price checker accepts.
+    pub fn buy_amount(self, quoted: U256, after_slippage: U256, checker_minimum: U256) -> U256 {
+        match self {
+            Self::Slippage => after_slippage,
+            Self::CheckerMinimum => checker_minimum,
+            Self::CheckerMidpoint => {
+                if checker_minimum >= quoted {
+                    checker_minimum
+                } else {
+                    checker_minimum + (quoted - checker_minimum) / 2
+                }
+            }
+            Self::CheckerBounded => after_slippage.max(checker_minimum),
+        }
+    }
+}
+
+impl FromStr for BuyAmountStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(strategy: &str) -> Result<Self> {
+        match strategy {
+            "slippage" => Ok(Self::Slippage),
+            "checker_minimum" => Ok(Self::CheckerMinimum),
+            "checker_midpoint" => Ok(Self::CheckerMidpoint),
+            "checker_bounded" => Ok(Self::CheckerBounded),
+            _ => Err(anyhow!(
+                "unknown buy amount strategy `{}`, expected `slippage`, `checker_minimum`, `checker_midpoint` or `checker_bounded`",
+                strategy
+            )),
+        }
+    }
+}
+
+impl fmt::Display for BuyAmountStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let strategy = match self {
+            Self::Slippage => "slippage",
+            Self::CheckerMinimum => "checker_minimum",
+            Self::CheckerMidpoint => "checker_midpoint",
+            Self::CheckerBounded => "checker_bounded",
+        };
+        f.write_str(strategy)
+    }
+}
+
+/// How a price checker lays out its data, which decides what of it goes to
+/// the checker's `EXPECTED_OUT_CALCULATOR`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceCheckerKind {
+    #[default]
+    Fixed, // passes all of its data on to the calculator
+    DynamicSlippage, // takes `(allowedSlippageInBps, data)` and only passes `data` on
+}
+
+impl PriceCheckerKind {
+    /// The part of a swap's price checker data that goes to the calculator.
+    pub fn calculator_data(self, price_checker_data: &Bytes) -> Result<Bytes> {
+        match self {
+            Self::Fixed => Ok(price_checker_data.clone()),
+            Self::DynamicSlippage => {
+                let tokens = abi::decode(
+                    &[ParamType::Uint(256), ParamType::Bytes],
+                    price_checker_data,
+                )
+                .context("dynamic slippage price checker data isn't `(uint256, bytes)`")?;
+                match &tokens[..] {
+                    [_, Token::Bytes(data)] => Ok(data.clone().into()),
+                    _ => unreachable!("decoded as `(uint256, bytes)`"),
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for PriceCheckerKind {
+    type Err = anyhow::Error;
+
+    fn from_str(kind: &str) -> Result<Self> {
+        match kind {
+            "fixed" => Ok(Self::Fixed),
+            "dynamic_slippage" => Ok(Self::DynamicSlippage),
+            _ => Err(anyhow!(
+                "unknown price checker kind `{}`, expected `fixed` or `dynamic_slippage`",
+                kind
+            )),
+        }
+    }
+}
+
+/// Binary search for the smallest buy amount, up to `quoted`, that `accepts`
+/// does. Price checkers accept any buy amount above some threshold, so this
+/// finds the threshold, to within a basis point of `quoted`.
+pub async fn search_checker_minimum<F, Fut>(quoted: U256, accepts: F) -> Result<U256>
+where
+    F: Fn(U256) -> Fut,
+    Fut: Future<Output = Result<bool>>,
+{
+    if !accepts(quoted).await? {
+        bail!(
+            "price checker rejects even the quoted buy amount of {}",
+            quoted
+        );
+    }
+
+    let precision = (quoted / 10000).max(U256::one());
+    let (mut rejected, mut accepted) = (U256::zero(), quoted);
+    if accepts(rejected).await? {
+        return Ok(rejected);
+    }
+    while accepted - rejected > precision {
+        let middle = rejected + (accepted - rejected) / 2;
+        if accepts(middle).await? {
+            accepted = middle;
+        } else {
+            rejected = middle;
+        }
+    }
+    Ok(accepted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[tokio::test]
+    async fn test_search_checker_minimum() {
+        let quoted = U256::exp10(18);
+        let threshold = U256::from(987_654_321_000_000_000u64);
+        let calls = Cell::new(0);
+        let minimum = search_checker_minimum(quoted, |buy_amount| {
+            calls.set(calls.get() + 1);
+            async move { Ok(buy_amount >= threshold) }
+        })
+        .await
+        .unwrap();
+        assert!(minimum >= threshold);
+        assert!(minimum - threshold <= quoted / 10000);
+        assert!(calls.get() <= 20);
+
+        let rejects_all = search_checker_minimum(quoted, |_| async { Ok(false) }).await;
+        assert!(rejects_all.is_err());
+        let accepts_all = search_checker_minimum(quoted, |_| async { Ok(true) }).await;
+        assert_eq!(accepts_all.unwrap(), U256::zero());
+    }
+
+    #[test]
+    fn test_buy_amount() {
+        let (quoted, after_slippage) = (U256::from(1000), U256::from(995));
+        let checker_minimum = U256::from(980);
+        assert_eq!(
+            BuyAmountStrategy::Slippage.buy_amount(quoted, after_slippage, checker_minimum),
+            U256::from(995)
+        );
+        assert_eq!(
+            BuyAmountStrategy::CheckerMinimum.buy_amount(quoted, after_slippage, checker_minimum),
+            U256::from(980)
+        );
+        assert_eq!(
+            BuyAmountStrategy::CheckerMidpoint.buy_amount(quoted, after_slippage, checker_minimum),
+            U256::from(990)
+        );
+        assert_eq!(
+            BuyAmountStrategy::CheckerBounded.buy_amount(quoted, U256::from(950), checker_minimum),
+            U256::from(980)
+        );
+        assert_eq!(
+            "checker_midpoint".parse::<BuyAmountStrategy>().unwrap(),
+            BuyAmountStrategy::CheckerMidpoint
+        );
+        assert!("midpoint".parse::<BuyAmountStrategy>().is_err());
+    }
+
+    #[test]
+    fn test_calculator_data() {
+        let data = Bytes::from(vec![1, 2, 3]);
+        let dynamic_data = Bytes::from(abi::encode(&[
+            Token::Uint(U256::from(50)),
+            Token::Bytes(data.to_vec()),
+        ]));
+        assert_eq!(
+            PriceCheckerKind::DynamicSlippage
+                .calculator_data(&dynamic_data)
+                .unwrap(),
+            data
+        );
+        assert_eq!(
+            PriceCheckerKind::Fixed
+                .calculator_data(&dynamic_data)
+                .unwrap(),
+            dynamic_data
+        );
+        assert!(PriceCheckerKind::DynamicSlippage
+            .calculator_data(&data)
+            .is_err());
+        assert_eq!(
+            "dynamic_slippage".parse::<PriceCheckerKind>().unwrap(),
+            PriceCheckerKind::DynamicSlippage
+        );
+    }
+}
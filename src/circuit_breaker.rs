@@ -0,0 +1,94 @@
+use std::time::{Duration, Instant};
+
+use crate::configuration::Configuration;
+
+/// Tracks a network's consecutive failures to talk to its node. After a
+/// failure, the loop polls less often, backing off exponentially. After
+/// `node_failure_threshold` failures in a row, the breaker opens and the
+/// loop stops handling swaps until the node answers again. If the node
+/// stays down for longer than `give_up_after_secs`, the bot gives up.
+#[derive(Debug, Default)]
+pub struct CircuitBreaker {
+    consecutive_failures: u32,
+    failing_since: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    /// Returns whether the breaker was open, i.e. whether the node just recovered.
+    pub fn record_success(&mut self, config: &Configuration) -> bool {
+        let was_open = self.is_open(config);
+        self.consecutive_failures = 0;
+        self.failing_since = None;
+        was_open
+    }
+
+    pub fn record_failure(&mut self) {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        self.failing_since.get_or_insert_with(Instant::now);
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures
+    }
+
+    pub fn is_open(&self, config: &Configuration) -> bool {
+        self.consecutive_failures >= config.node_failure_threshold
+    }
+
+    pub fn should_give_up(&self, config: &Configuration) -> bool {
+        match (self.failing_since, config.give_up_after_secs) {
+            (Some(failing_since), Some(give_up_after_secs)) => {
+                failing_since.elapsed() >= Duration::from_secs(give_up_after_secs)
+            }
+            _ => false,
+        }
+    }
+
+    /// How long to wait before polling again: the polling frequency, doubled
+    /// for every consecutive failure, up to `max_backoff_secs`.
+    pub fn delay(&self, config: &Configuration) -> Duration {
+        let polling_frequency = Duration::from_secs(config.polling_frequency_secs);
+        if self.consecutive_failures == 0 {
+            return polling_frequency;
+        }
+
+        let backoff = polling_frequency
+            .checked_mul(1 << (self.consecutive_failures - 1).min(16))
+            .unwrap_or(Duration::MAX);
+        backoff
+            .min(Duration::from_secs(config.max_backoff_secs))
+            .max(polling_frequency)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let config = Configuration {
+            node_failure_threshold: 3,
+            give_up_after_secs: Some(0),
+            ..Configuration::for_tests()
+        };
+        let mut breaker = CircuitBreaker::default();
+        assert_eq!(breaker.delay(&config), Duration::from_secs(10));
+        assert!(!breaker.should_give_up(&config));
+
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.delay(&config), Duration::from_secs(20));
+        assert!(!breaker.is_open(&config));
+
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.delay(&config), Duration::from_secs(60));
+        assert!(breaker.is_open(&config));
+        assert!(breaker.should_give_up(&config));
+
+        assert!(breaker.record_success(&config));
+        assert!(!breaker.is_open(&config));
+        assert_eq!(breaker.delay(&config), Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,204 @@
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::types::{Address, Bytes, U256};
+use std::collections::HashMap;
+
+/// What the binary was asked to do. With no subcommand, the bot runs as usual.
+/// `check-config` validates the configuration against the chain and the CoW
+/// API, and the swap commands are helpers for exercising the bot on a testnet
+/// or a local devnet.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Run,
+    CheckConfig,
+    RequestSwap(RequestSwapArgs),
+    CancelSwap(CancelSwapArgs),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestSwapArgs {
+    pub milkman: Option<Address>, // defaults to the first configured deployment
+    pub from_token: Address,
+    pub to_token: Address,
+    pub amount_in: U256,
+    pub receiver: Option<Address>, // defaults to the signer
+    pub price_checker: Address,
+    pub price_checker_data: Bytes,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CancelSwapArgs {
+    pub order_contract: Address,
+    pub from_block: Option<u64>, // where to start looking for the `SwapRequested` event
+}
+
+impl Command {
+    /// Parse a command from the process arguments, excluding the binary name.
+    /// Flags are of the form `--name value`.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut args = args.into_iter();
+
+        let command = match args.next() {
+            None => return Ok(Self::Run),
+            Some(command) => command,
+        };
+        let mut flags = Flags::parse(args)?;
+
+        let command = match command.as_str() {
+            "run" => Self::Run,
+            "check-config" => Self::CheckConfig,
+            "request-swap" => Self::RequestSwap(RequestSwapArgs {
+                milkman: flags.optional("milkman")?,
+                from_token: flags.required("from-token")?,
+                to_token: flags.required("to-token")?,
+                amount_in: U256::from_dec_str(&flags.take_required("amount-in")?)
+                    .context("`--amount-in` must be a decimal integer")?,
+                receiver: flags.optional("receiver")?,
+                price_checker: flags.required("price-checker")?,
+                price_checker_data: flags.optional("price-checker-data")?.unwrap_or_default(),
+            }),
+            "cancel-swap" => Self::CancelSwap(CancelSwapArgs {
+                order_contract: flags.required("order-contract")?,
+                from_block: flags.optional("from-block")?,
+            }),
+            other => bail!("unknown command `{}`", other),
+        };
+
+        flags.ensure_consumed()?;
+
+        Ok(command)
+    }
+}
+
+struct Flags(HashMap<String, String>);
+
+impl Flags {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut flags = HashMap::new();
+
+        while let Some(arg) = args.next() {
+            let name = arg
+                .strip_prefix("--")
+                .ok_or_else(|| anyhow!("unexpected argument `{}`", arg))?
+                .to_string();
+            let value = args
+                .next()
+                .ok_or_else(|| anyhow!("missing value for `--{}`", name))?;
+            if flags.insert(name.clone(), value).is_some() {
+                bail!("`--{}` passed more than once", name);
+            }
+        }
+
+        Ok(Self(flags))
+    }
+
+    fn take_required(&mut self, name: &str) -> Result<String> {
+        self.0
+            .remove(name)
+            .ok_or_else(|| anyhow!("missing required flag `--{}`", name))
+    }
+
+    fn required<T>(&mut self, name: &str) -> Result<T>
+    where
+        T: std::str::FromStr,
+        T::Err: std::error::Error + Send + Sync + 'static,
+    {
+        self.take_required(name)?
+            .parse()
+            .with_context(|| format!("unable to parse `--{}`", name))
+    }
+
+    fn optional<T>(&mut self, name: &str) -> Result<Option<T>>
+    where
+        T: std::str::FromStr,
+        T::Err: std::error::Error + Send + Sync + 'static,
+    {
+        self.0
+            .remove(name)
+            .map(|value| value.parse())
+            .transpose()
+            .with_context(|| format!("unable to parse `--{}`", name))
+    }
+
+    fn ensure_consumed(&self) -> Result<()> {
+        match self.0.keys().next() {
+            Some(name) => bail!("unknown flag `--{}`", name),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(Command::parse(args(&[])).unwrap(), Command::Run);
+        assert_eq!(Command::parse(args(&["run"])).unwrap(), Command::Run);
+        assert_eq!(
+            Command::parse(args(&["check-config"])).unwrap(),
+            Command::CheckConfig
+        );
+
+        let from_token = Address::random();
+        let to_token = Address::random();
+        let price_checker = Address::random();
+        let command = Command::parse(args(&[
+            "request-swap",
+            "--from-token",
+            &format!("{:#x}", from_token),
+            "--to-token",
+            &format!("{:#x}", to_token),
+            "--amount-in",
+            "1000000000000000000000",
+            "--price-checker",
+            &format!("{:#x}", price_checker),
+            "--price-checker-data",
+            "0x1234",
+        ]))
+        .unwrap();
+        assert_eq!(
+            command,
+            Command::RequestSwap(RequestSwapArgs {
+                milkman: None,
+                from_token,
+                to_token,
+                amount_in: U256::exp10(21),
+                receiver: None,
+                price_checker,
+                price_checker_data: vec![0x12, 0x34].into(),
+            })
+        );
+
+        let order_contract = Address::random();
+        let command = Command::parse(args(&[
+            "cancel-swap",
+            "--order-contract",
+            &format!("{:#x}", order_contract),
+        ]))
+        .unwrap();
+        assert_eq!(
+            command,
+            Command::CancelSwap(CancelSwapArgs {
+                order_contract,
+                from_block: None,
+            })
+        );
+
+        assert!(Command::parse(args(&["cancel-swap"])).is_err());
+        assert!(Command::parse(args(&["cancel-swap", "--order-contract"])).is_err());
+        assert!(Command::parse(args(&["swap"])).is_err());
+        assert!(Command::parse(args(&[
+            "cancel-swap",
+            "--order-contract",
+            &format!("{:#x}", order_contract),
+            "--unknown",
+            "1",
+        ]))
+        .is_err());
+    }
+}
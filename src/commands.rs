@@ -0,0 +1,67 @@
+use anyhow::{Context, Result};
+use ethers::signers::Signer;
+use std::env;
+
+use crate::cli::{CancelSwapArgs, RequestSwapArgs};
+use crate::configuration::Configuration;
+use crate::ethereum_client::EthereumClient;
+use crate::types::Swap;
+
+/// Request a Milkman swap signed by `PRIVATE_KEY`, approving the token first.
+pub async fn request_swap(config: &Configuration, args: RequestSwapArgs) -> Result<()> {
+    let eth_client = EthereumClient::new(config)?;
+    let signer = eth_client.get_signing_provider(&private_key()?).await?;
+    let signer_address = signer.signer().address();
+
+    let swap = Swap {
+        milkman: match args.milkman {
+            Some(address) => config
+                .milkman_deployments
+                .iter()
+                .find(|deployment| deployment.address == address)
+                .copied()
+                .unwrap_or_else(|| address.into()),
+            None => config.milkman_deployments[0],
+        },
+        order_contract: Default::default(), // not known until the swap is requested
+        order_creator: signer_address,
+        receiver: args.receiver.unwrap_or(signer_address),
+        from_token: args.from_token,
+        to_token: args.to_token,
+        amount_in: args.amount_in,
+        price_checker: args.price_checker,
+        price_checker_data: args.price_checker_data,
+        provenance: None,
+    };
+    tracing::info!("Requesting swap: {:?}", swap);
+
+    let order_contract = eth_client.request_swap(signer, &swap).await?;
+    tracing::info!("Swap requested with order contract {:#x}", order_contract);
+
+    Ok(())
+}
+
+/// Cancel a Milkman swap signed by `PRIVATE_KEY`, which must be the swap's
+/// creator. The parameters are rebuilt from the `SwapRequested` event.
+pub async fn cancel_swap(config: &Configuration, args: CancelSwapArgs) -> Result<()> {
+    let eth_client = EthereumClient::new(config)?;
+    let signer = eth_client.get_signing_provider(&private_key()?).await?;
+
+    let from_block = args
+        .from_block
+        .or(config.starting_block_number)
+        .unwrap_or_default();
+    let swap = eth_client
+        .find_requested_swap(args.order_contract, from_block)
+        .await?;
+    tracing::info!("Cancelling swap: {:?}", swap);
+
+    let tx_hash = eth_client.cancel_swap(signer, &swap).await?;
+    tracing::info!("Swap cancelled in transaction {:#x}", tx_hash);
+
+    Ok(())
+}
+
+fn private_key() -> Result<String> {
+    env::var("PRIVATE_KEY").context("`PRIVATE_KEY` must be set to sign transactions")
+}
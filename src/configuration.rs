@@ -1,72 +1,938 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use ethers::types::Address;
 use log::debug;
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
 
-use crate::constants::{MAINNET_HASH_HELPER_ADDRESS, PROD_MILKMAN_ADDRESS};
+use crate::buy_amount::{BuyAmountStrategy, PriceCheckerKind};
+use crate::milkman_adapter::MilkmanDeployment;
+use crate::network::Network;
+use crate::slippage::SlippageRule;
 
+/// Environment variable that points to an optional TOML configuration file.
+pub const CONFIG_FILE_VARIABLE: &str = "MILKMAN_CONFIG_FILE";
+
+/// Environment variables that hold a single network's settings, such as its
+/// addresses, so they can't be set when several networks are run.
+const NETWORK_VARIABLES: [&str; 10] = [
+    "CHAIN_ID",
+    "MILKMAN_ADDRESS",
+    "HASH_HELPER_ADDRESS",
+    "SETTLEMENT_ADDRESS",
+    "STATE_HELPER_ADDRESS",
+    "STARTING_BLOCK_NUMBER",
+    "NODE_BASE_URL",
+    "COW_API_BASE_URL",
+    "MAX_FEE_BPS_BY_TOKEN",
+    "PRICE_CHECKER_KINDS",
+];
+
+/// Settings for the whole process, plus the configuration of every network
+/// the process runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BotConfiguration {
+    pub networks: Vec<Configuration>,
+    pub metrics_port: Option<u16>,
+    pub state_dir: Option<PathBuf>, // where each network's queue and checkpoint are kept
+    pub shutdown_timeout_secs: u64,
+    pub exit_on_give_up: bool, // stop every network when one gives up, rather than just that one
+}
+
+/// The configuration of a single network.
 #[derive(Debug, Clone, PartialEq)]
 pub struct Configuration {
     pub infura_api_key: Option<String>,
-    pub network: String, // whatever infura accepts as a network e.g., 'mainnet' or 'goerli'
-    pub milkman_address: Address,
+    pub network: Network,
+    pub milkman_deployments: Vec<MilkmanDeployment>, // every Milkman deployment to watch
     pub hash_helper_address: Address,
+    pub settlement_address: Address, // GPv2Settlement
+    pub state_helper_address: Option<Address>,
     pub starting_block_number: Option<u64>,
     pub polling_frequency_secs: u64,
     pub node_base_url: Option<String>,
+    pub cow_api_base_url: String,
     pub slippage_tolerance_bps: u16,
+    pub slippage_step_bps: u16, // added for every order of a swap that expired unfilled
+    pub max_slippage_bps: u16,  // the ceiling for escalated slippage
+    pub buy_amount_strategy: BuyAmountStrategy, // how to set orders' buy amounts
+    pub price_checker_kinds: HashMap<Address, PriceCheckerKind>, // checkers not listed are `Fixed`
+    pub limit_orders: bool,     // post limit orders for swaps the market can't fill yet
+    pub limit_order_validity_secs: u64,
+    pub app_code: String, // identifies the bot in orders' appData
+    pub bot_instance: Option<String>,
+    pub referrer_address: Option<Address>,
+    pub partner_fee_bps: Option<u16>,
+    pub partner_fee_recipient: Option<Address>,
+    pub slippage_rules: Vec<SlippageRule>, // the first that matches a swap overrides `slippage_tolerance_bps`
+    pub node_failure_threshold: u32, // consecutive node failures before swaps stop being handled
+    pub max_backoff_secs: u64,
+    pub give_up_after_secs: Option<u64>, // how long the node can be down before the bot exits
+    pub max_concurrent_swaps: usize,
+    pub park_after_failures: u32,
+    pub park_after_secs: Option<u64>, // the age of a swap after which a failure parks it
+    pub parked_recheck_secs: u64,
+    pub max_order_retries: u32, // re-quotes and resubmits after a recoverable order error
+    pub max_fee_bps: u16,       // the share of a swap's amount its fee may take
+    pub max_fee_bps_by_token: HashMap<Address, u16>, // overrides `max_fee_bps` by sell token
+    pub fee_recheck_secs: u64,  // how often swaps whose fee is too high are requoted
+}
+
+/// The settings that changed when a configuration was reloaded.
+#[derive(Debug, Default, PartialEq)]
+pub struct Reload {
+    pub applied: Vec<&'static str>,
+    pub needs_restart: Vec<&'static str>,
+}
+
+impl Reload {
+    fn live<T: PartialEq>(&mut self, setting: &'static str, current: &mut T, reloaded: T) {
+        if *current != reloaded {
+            *current = reloaded;
+            self.applied.push(setting);
+        }
+    }
+
+    fn restart<T: PartialEq>(&mut self, setting: &'static str, current: &T, reloaded: T) {
+        if *current != reloaded {
+            self.needs_restart.push(setting);
+        }
+    }
+}
+
+/// The layout of the configuration file. Settings at the top level apply to
+/// every network, and each `[networks.<name>]` section holds the settings of
+/// one network, overriding the top level where both are set.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigurationFile {
+    network: Option<String>,
+    metrics_port: Option<u16>,
+    state_dir: Option<PathBuf>,
+    shutdown_timeout_secs: Option<u64>,
+    exit_on_give_up: Option<bool>,
+    polling_frequency_secs: Option<u64>,
+    slippage_tolerance_bps: Option<u16>,
+    slippage_step_bps: Option<u16>,
+    max_slippage_bps: Option<u16>,
+    buy_amount_strategy: Option<BuyAmountStrategy>,
+    limit_orders: Option<bool>,
+    limit_order_validity_secs: Option<u64>,
+    app_code: Option<String>,
+    bot_instance: Option<String>,
+    referrer_address: Option<Address>,
+    partner_fee_bps: Option<u16>,
+    partner_fee_recipient: Option<Address>,
+    node_failure_threshold: Option<u32>,
+    max_backoff_secs: Option<u64>,
+    give_up_after_secs: Option<u64>,
+    max_concurrent_swaps: Option<usize>,
+    park_after_failures: Option<u32>,
+    park_after_secs: Option<u64>,
+    parked_recheck_secs: Option<u64>,
+    max_order_retries: Option<u32>,
+    max_fee_bps: Option<u16>,
+    max_fee_bps_by_token: Option<HashMap<Address, u16>>,
+    fee_recheck_secs: Option<u64>,
+    slippage_rules: Option<Vec<SlippageRule>>,
+    #[serde(default)]
+    networks: HashMap<String, NetworkSection>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NetworkSection {
+    chain_id: Option<u64>,
+    infura_api_key: Option<String>,
+    node_base_url: Option<String>,
+    cow_api_base_url: Option<String>,
+    milkman_deployments: Option<Vec<DeploymentEntry>>,
+    hash_helper_address: Option<Address>,
+    settlement_address: Option<Address>,
+    state_helper_address: Option<Address>,
+    starting_block_number: Option<u64>,
+    polling_frequency_secs: Option<u64>,
+    slippage_tolerance_bps: Option<u16>,
+    slippage_step_bps: Option<u16>,
+    max_slippage_bps: Option<u16>,
+    buy_amount_strategy: Option<BuyAmountStrategy>,
+    price_checker_kinds: Option<HashMap<Address, PriceCheckerKind>>,
+    limit_orders: Option<bool>,
+    limit_order_validity_secs: Option<u64>,
+    app_code: Option<String>,
+    bot_instance: Option<String>,
+    referrer_address: Option<Address>,
+    partner_fee_bps: Option<u16>,
+    partner_fee_recipient: Option<Address>,
+    slippage_rules: Option<Vec<SlippageRule>>,
+    node_failure_threshold: Option<u32>,
+    max_backoff_secs: Option<u64>,
+    give_up_after_secs: Option<u64>,
+    max_concurrent_swaps: Option<usize>,
+    park_after_failures: Option<u32>,
+    park_after_secs: Option<u64>,
+    parked_recheck_secs: Option<u64>,
+    max_order_retries: Option<u32>,
+    max_fee_bps: Option<u16>,
+    max_fee_bps_by_token: Option<HashMap<Address, u16>>,
+    fee_recheck_secs: Option<u64>,
+}
+
+/// A Milkman deployment in the file, either as `"<address>[:<version>]"` or as
+/// `{ address = "...", version = "..." }`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum DeploymentEntry {
+    Short(String),
+    Full(MilkmanDeployment),
+}
+
+impl TryFrom<DeploymentEntry> for MilkmanDeployment {
+    type Error = anyhow::Error;
+
+    fn try_from(entry: DeploymentEntry) -> Result<Self> {
+        match entry {
+            DeploymentEntry::Short(deployment) => deployment.parse(),
+            DeploymentEntry::Full(deployment) => Ok(deployment),
+        }
+    }
+}
+
+impl ConfigurationFile {
+    fn from_environment() -> Result<Self> {
+        match collect_optional_environment_variable(CONFIG_FILE_VARIABLE)? {
+            Some(path) => Self::read(&path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    fn read(path: &str) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("unable to read configuration file `{}`", path))?;
+        Self::parse(&contents).with_context(|| format!("invalid configuration file `{}`", path))
+    }
+
+    fn parse(contents: &str) -> Result<Self> {
+        Ok(toml::from_str(contents)?)
+    }
+
+    /// The networks to run: those named in `MILKMAN_NETWORK` (comma-separated),
+    /// otherwise every network with a section in the file, otherwise mainnet.
+    fn network_names(
+        &self,
+        get_variable: impl Fn(&str) -> Result<Option<String>>,
+    ) -> Result<Vec<String>> {
+        let names = match get_variable("MILKMAN_NETWORK")?.or_else(|| self.network.clone()) {
+            Some(names) => names
+                .split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect(),
+            None if !self.networks.is_empty() => {
+                let mut names: Vec<String> = self.networks.keys().cloned().collect();
+                names.sort();
+                names
+            }
+            None => vec!["mainnet".to_string()],
+        };
+
+        if names.is_empty() {
+            bail!("no network selected");
+        }
+        for (i, name) in names.iter().enumerate() {
+            if names[..i].contains(name) {
+                bail!("network `{}` selected more than once", name);
+            }
+        }
+
+        Ok(names)
+    }
+
+    fn network_section(&self, network: &str) -> Result<NetworkSection> {
+        if self.networks.is_empty() {
+            return Ok(NetworkSection::default());
+        }
+
+        self.networks.get(network).cloned().ok_or_else(|| {
+            anyhow!(
+                "network is `{}` but the configuration file has no `[networks.{}]` section",
+                network,
+                network
+            )
+        })
+    }
+}
+
+impl BotConfiguration {
+    /// Build the configuration from the file named by `MILKMAN_CONFIG_FILE`, if
+    /// set, with environment variables taking precedence over the file.
+    /// Environment variables apply to every network that is run.
+    pub fn get_from_environment() -> Result<Self> {
+        Self::resolve(
+            &ConfigurationFile::from_environment()?,
+            collect_optional_environment_variable,
+        )
+    }
+
+    fn resolve(
+        file: &ConfigurationFile,
+        get_variable: impl Fn(&str) -> Result<Option<String>>,
+    ) -> Result<Self> {
+        let network_names = file.network_names(&get_variable)?;
+        if network_names.len() > 1 {
+            for key in NETWORK_VARIABLES {
+                if get_variable(key)?.is_some() {
+                    bail!(
+                        "environment variable {} can't be set when {} networks are run, set it in each network's section of the configuration file instead",
+                        key,
+                        network_names.len()
+                    );
+                }
+            }
+        }
+        let networks = network_names
+            .iter()
+            .map(|name| {
+                Configuration::resolve_network(file, name, &get_variable)
+                    .with_context(|| format!("invalid configuration for network `{}`", name))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let metrics_port =
+            parse_optional_variable(&get_variable, "METRICS_PORT")?.or(file.metrics_port);
+        let state_dir = get_variable("STATE_DIR")?
+            .map(PathBuf::from)
+            .or_else(|| file.state_dir.clone());
+        let shutdown_timeout_secs =
+            parse_optional_variable(&get_variable, "SHUTDOWN_TIMEOUT_SECS")?
+                .or(file.shutdown_timeout_secs)
+                .unwrap_or(20);
+        let exit_on_give_up = parse_optional_variable(&get_variable, "EXIT_ON_GIVE_UP")?
+            .or(file.exit_on_give_up)
+            .unwrap_or(false);
+
+        Ok(Self {
+            networks,
+            metrics_port,
+            state_dir,
+            shutdown_timeout_secs,
+            exit_on_give_up,
+        })
+    }
 }
 
 impl Configuration {
+    /// Like `BotConfiguration::get_from_environment`, for commands that act on
+    /// a single network.
     pub fn get_from_environment() -> Result<Self> {
-        let infura_api_key = collect_optional_environment_variable("INFURA_API_KEY")?;
-        let node_base_url = collect_optional_environment_variable("NODE_BASE_URL")?;
+        Self::resolve(
+            &ConfigurationFile::from_environment()?,
+            collect_optional_environment_variable,
+        )
+    }
 
-        if infura_api_key.is_none() && node_base_url.is_none() {
-            return Err(anyhow!(
-                "either `infura_api_key` or `node_base_url` must be set"
-            ));
+    fn resolve(
+        file: &ConfigurationFile,
+        get_variable: impl Fn(&str) -> Result<Option<String>>,
+    ) -> Result<Self> {
+        match file.network_names(&get_variable)?.as_slice() {
+            [name] => Self::resolve_network(file, name, get_variable),
+            names => bail!(
+                "this command runs on a single network but {} are selected, pick one with MILKMAN_NETWORK",
+                names.len()
+            ),
         }
+    }
+
+    fn resolve_network(
+        file: &ConfigurationFile,
+        network_name: &str,
+        get_variable: impl Fn(&str) -> Result<Option<String>>,
+    ) -> Result<Self> {
+        let section = file.network_section(network_name)?;
+        let chain_id = parse_optional_variable(&get_variable, "CHAIN_ID")?.or(section.chain_id);
+        let network = Network::from_name(network_name, chain_id)?;
 
-        let network = collect_optional_environment_variable("MILKMAN_NETWORK")?
-            .unwrap_or_else(|| "mainnet".to_string());
-        let milkman_address = collect_optional_environment_variable("MILKMAN_ADDRESS")?
-            .as_deref()
-            .unwrap_or(PROD_MILKMAN_ADDRESS)
-            .parse()?;
+        let infura_api_key = get_variable("INFURA_API_KEY")?.or(section.infura_api_key);
+        let node_base_url = get_variable("NODE_BASE_URL")?.or(section.node_base_url);
+        let cow_api_base_url = get_variable("COW_API_BASE_URL")?
+            .or(section.cow_api_base_url)
+            .or_else(|| network.cow_api_base_url().map(str::to_string))
+            .with_context(|| no_default(&network, "cow_api_base_url"))?;
+        let milkman_deployments = match get_variable("MILKMAN_ADDRESS")? {
+            Some(deployments) => Some(
+                deployments
+                    .split(',')
+                    .map(str::parse)
+                    .collect::<Result<Vec<MilkmanDeployment>>>()
+                    .context("unable to parse environment variable MILKMAN_ADDRESS")?,
+            ),
+            None => section
+                .milkman_deployments
+                .map(|entries| {
+                    entries
+                        .into_iter()
+                        .map(MilkmanDeployment::try_from)
+                        .collect::<Result<Vec<_>>>()
+                })
+                .transpose()
+                .context("invalid `milkman_deployments`")?,
+        }
+        .or_else(|| {
+            network
+                .default_milkman_address()
+                .map(|address| vec![address.into()])
+        })
+        .with_context(|| no_default(&network, "milkman_deployments"))?;
+        let hash_helper_address = parse_optional_variable(&get_variable, "HASH_HELPER_ADDRESS")?
+            .or(section.hash_helper_address)
+            .or_else(|| network.default_hash_helper_address())
+            .with_context(|| no_default(&network, "hash_helper_address"))?;
+        let settlement_address = parse_optional_variable(&get_variable, "SETTLEMENT_ADDRESS")?
+            .or(section.settlement_address)
+            .or_else(|| network.default_settlement_address())
+            .with_context(|| no_default(&network, "settlement_address"))?;
+        let state_helper_address = parse_optional_variable(&get_variable, "STATE_HELPER_ADDRESS")?
+            .or(section.state_helper_address);
+        let starting_block_number =
+            parse_optional_variable(&get_variable, "STARTING_BLOCK_NUMBER")?
+                .or(section.starting_block_number);
         let polling_frequency_secs =
-            collect_optional_environment_variable("POLLING_FREQUENCY_SECS")?
-                .map(|var| var.parse::<u64>())
-                .transpose()?
+            parse_optional_variable(&get_variable, "POLLING_FREQUENCY_SECS")?
+                .or(section.polling_frequency_secs)
+                .or(file.polling_frequency_secs)
                 .unwrap_or(10);
-        let hash_helper_address = collect_optional_environment_variable("HASH_HELPER_ADDRESS")?
-            .as_deref()
-            .unwrap_or(MAINNET_HASH_HELPER_ADDRESS)
-            .parse()?;
+        let slippage_tolerance_bps =
+            parse_optional_variable(&get_variable, "SLIPPAGE_TOLERANCE_BPS")?
+                .or(section.slippage_tolerance_bps)
+                .or(file.slippage_tolerance_bps)
+                .unwrap_or(50);
+        let slippage_step_bps = parse_optional_variable(&get_variable, "SLIPPAGE_STEP_BPS")?
+            .or(section.slippage_step_bps)
+            .or(file.slippage_step_bps)
+            .unwrap_or(0);
+        let max_slippage_bps = parse_optional_variable(&get_variable, "MAX_SLIPPAGE_BPS")?
+            .or(section.max_slippage_bps)
+            .or(file.max_slippage_bps)
+            .unwrap_or(500);
+        let buy_amount_strategy = parse_optional_variable(&get_variable, "BUY_AMOUNT_STRATEGY")?
+            .or(section.buy_amount_strategy)
+            .or(file.buy_amount_strategy)
+            .unwrap_or(BuyAmountStrategy::default());
+        let price_checker_kinds = match get_variable("PRICE_CHECKER_KINDS")? {
+            Some(value) => parse_address_map(&value)
+                .context("unable to parse environment variable PRICE_CHECKER_KINDS")?,
+            None => section.price_checker_kinds.unwrap_or_default(),
+        };
+        let limit_orders = parse_optional_variable(&get_variable, "LIMIT_ORDERS")?
+            .or(section.limit_orders)
+            .or(file.limit_orders)
+            .unwrap_or(false);
+        let limit_order_validity_secs =
+            parse_optional_variable(&get_variable, "LIMIT_ORDER_VALIDITY_SECS")?
+                .or(section.limit_order_validity_secs)
+                .or(file.limit_order_validity_secs)
+                .unwrap_or(604800);
+        let app_code = parse_optional_variable(&get_variable, "APP_CODE")?
+            .or(section.app_code)
+            .or_else(|| file.app_code.clone())
+            .unwrap_or_else(|| "milkman-bot".to_string());
+        let bot_instance = parse_optional_variable(&get_variable, "BOT_INSTANCE")?
+            .or(section.bot_instance)
+            .or_else(|| file.bot_instance.clone());
+        let referrer_address = parse_optional_variable(&get_variable, "REFERRER_ADDRESS")?
+            .or(section.referrer_address)
+            .or(file.referrer_address);
+        let partner_fee_bps = parse_optional_variable(&get_variable, "PARTNER_FEE_BPS")?
+            .or(section.partner_fee_bps)
+            .or(file.partner_fee_bps);
+        let partner_fee_recipient =
+            parse_optional_variable(&get_variable, "PARTNER_FEE_RECIPIENT")?
+                .or(section.partner_fee_recipient)
+                .or(file.partner_fee_recipient);
+        // a network's rules come first, so they win over the top level's
+        let slippage_rules = section
+            .slippage_rules
+            .into_iter()
+            .chain(file.slippage_rules.clone())
+            .flatten()
+            .collect();
+        let node_failure_threshold =
+            parse_optional_variable(&get_variable, "NODE_FAILURE_THRESHOLD")?
+                .or(section.node_failure_threshold)
+                .or(file.node_failure_threshold)
+                .unwrap_or(5);
+        let max_backoff_secs = parse_optional_variable(&get_variable, "MAX_BACKOFF_SECS")?
+            .or(section.max_backoff_secs)
+            .or(file.max_backoff_secs)
+            .unwrap_or(300);
+        let give_up_after_secs = parse_optional_variable(&get_variable, "GIVE_UP_AFTER_SECS")?
+            .or(section.give_up_after_secs)
+            .or(file.give_up_after_secs);
+        let max_concurrent_swaps = parse_optional_variable(&get_variable, "MAX_CONCURRENT_SWAPS")?
+            .or(section.max_concurrent_swaps)
+            .or(file.max_concurrent_swaps)
+            .unwrap_or(8);
+        let park_after_failures = parse_optional_variable(&get_variable, "PARK_AFTER_FAILURES")?
+            .or(section.park_after_failures)
+            .or(file.park_after_failures)
+            .unwrap_or(20);
+        let park_after_secs = parse_optional_variable(&get_variable, "PARK_AFTER_SECS")?
+            .or(section.park_after_secs)
+            .or(file.park_after_secs);
+        let parked_recheck_secs = parse_optional_variable(&get_variable, "PARKED_RECHECK_SECS")?
+            .or(section.parked_recheck_secs)
+            .or(file.parked_recheck_secs)
+            .unwrap_or(3600);
+        let max_order_retries = parse_optional_variable(&get_variable, "MAX_ORDER_RETRIES")?
+            .or(section.max_order_retries)
+            .or(file.max_order_retries)
+            .unwrap_or(2);
+        let max_fee_bps = parse_optional_variable(&get_variable, "MAX_FEE_BPS")?
+            .or(section.max_fee_bps)
+            .or(file.max_fee_bps)
+            .unwrap_or(10000);
+        let max_fee_bps_by_token = match get_variable("MAX_FEE_BPS_BY_TOKEN")? {
+            Some(value) => parse_address_map(&value)
+                .context("unable to parse environment variable MAX_FEE_BPS_BY_TOKEN")?,
+            None => file
+                .max_fee_bps_by_token
+                .iter()
+                .chain(&section.max_fee_bps_by_token)
+                .flatten()
+                .map(|(token, bps)| (*token, *bps))
+                .collect(),
+        };
+        let fee_recheck_secs = parse_optional_variable(&get_variable, "FEE_RECHECK_SECS")?
+            .or(section.fee_recheck_secs)
+            .or(file.fee_recheck_secs)
+            .unwrap_or(600);
 
-        let starting_block_number =
-            match collect_optional_environment_variable("STARTING_BLOCK_NUMBER")? {
-                Some(block_num) => block_num.parse::<u64>().ok(),
-                None => None,
-            };
+        let config = Self {
+            infura_api_key,
+            network,
+            milkman_deployments,
+            hash_helper_address,
+            settlement_address,
+            state_helper_address,
+            starting_block_number,
+            polling_frequency_secs,
+            node_base_url,
+            cow_api_base_url,
+            slippage_tolerance_bps,
+            slippage_step_bps,
+            max_slippage_bps,
+            buy_amount_strategy,
+            price_checker_kinds,
+            limit_orders,
+            limit_order_validity_secs,
+            app_code,
+            bot_instance,
+            referrer_address,
+            partner_fee_bps,
+            partner_fee_recipient,
+            slippage_rules,
+            node_failure_threshold,
+            max_backoff_secs,
+            give_up_after_secs,
+            max_concurrent_swaps,
+            park_after_failures,
+            park_after_secs,
+            parked_recheck_secs,
+            max_order_retries,
+            max_fee_bps,
+            max_fee_bps_by_token,
+            fee_recheck_secs,
+        };
+        config.validate()?;
 
-        let slippage_tolerance_bps =
-            match collect_optional_environment_variable("SLIPPAGE_TOLERANCE_BPS")? {
-                Some(block_num) => block_num.parse::<u16>().expect("Unable to parse slippage tolerance factor"),
-                None => 50,
-            };
+        Ok(config)
+    }
 
-        Ok(Self {
+    /// Take on the settings of a reloaded configuration that are safe to
+    /// change while running. Settings that only take effect after a restart
+    /// are left as they are, and reported.
+    pub fn reload(&mut self, reloaded: Configuration) -> Reload {
+        // destructured so that adding a setting means deciding how it reloads
+        let Configuration {
             infura_api_key,
             network,
-            milkman_address,
+            milkman_deployments,
             hash_helper_address,
+            settlement_address,
+            state_helper_address,
             starting_block_number,
             polling_frequency_secs,
             node_base_url,
-            slippage_tolerance_bps
-        })
+            cow_api_base_url,
+            slippage_tolerance_bps,
+            slippage_step_bps,
+            max_slippage_bps,
+            buy_amount_strategy,
+            price_checker_kinds,
+            limit_orders,
+            limit_order_validity_secs,
+            app_code,
+            bot_instance,
+            referrer_address,
+            partner_fee_bps,
+            partner_fee_recipient,
+            slippage_rules,
+            node_failure_threshold,
+            max_backoff_secs,
+            give_up_after_secs,
+            max_concurrent_swaps,
+            park_after_failures,
+            park_after_secs,
+            parked_recheck_secs,
+            max_order_retries,
+            max_fee_bps,
+            max_fee_bps_by_token,
+            fee_recheck_secs,
+        } = reloaded;
+
+        let mut reload = Reload::default();
+        reload.live("infura_api_key", &mut self.infura_api_key, infura_api_key);
+        reload.restart("network", &self.network, network);
+        reload.restart(
+            "milkman_deployments",
+            &self.milkman_deployments,
+            milkman_deployments,
+        );
+        reload.restart(
+            "hash_helper_address",
+            &self.hash_helper_address,
+            hash_helper_address,
+        );
+        reload.restart(
+            "settlement_address",
+            &self.settlement_address,
+            settlement_address,
+        );
+        reload.restart(
+            "state_helper_address",
+            &self.state_helper_address,
+            state_helper_address,
+        );
+        reload.restart(
+            "starting_block_number",
+            &self.starting_block_number,
+            starting_block_number,
+        );
+        reload.live(
+            "polling_frequency_secs",
+            &mut self.polling_frequency_secs,
+            polling_frequency_secs,
+        );
+        reload.live("node_base_url", &mut self.node_base_url, node_base_url);
+        reload.live(
+            "cow_api_base_url",
+            &mut self.cow_api_base_url,
+            cow_api_base_url,
+        );
+        reload.live(
+            "slippage_tolerance_bps",
+            &mut self.slippage_tolerance_bps,
+            slippage_tolerance_bps,
+        );
+        reload.live(
+            "slippage_step_bps",
+            &mut self.slippage_step_bps,
+            slippage_step_bps,
+        );
+        reload.live(
+            "max_slippage_bps",
+            &mut self.max_slippage_bps,
+            max_slippage_bps,
+        );
+        reload.live(
+            "buy_amount_strategy",
+            &mut self.buy_amount_strategy,
+            buy_amount_strategy,
+        );
+        reload.live(
+            "price_checker_kinds",
+            &mut self.price_checker_kinds,
+            price_checker_kinds,
+        );
+        reload.live("limit_orders", &mut self.limit_orders, limit_orders);
+        reload.live(
+            "limit_order_validity_secs",
+            &mut self.limit_order_validity_secs,
+            limit_order_validity_secs,
+        );
+        reload.live("app_code", &mut self.app_code, app_code);
+        reload.live("bot_instance", &mut self.bot_instance, bot_instance);
+        reload.live(
+            "referrer_address",
+            &mut self.referrer_address,
+            referrer_address,
+        );
+        reload.live(
+            "partner_fee_bps",
+            &mut self.partner_fee_bps,
+            partner_fee_bps,
+        );
+        reload.live(
+            "partner_fee_recipient",
+            &mut self.partner_fee_recipient,
+            partner_fee_recipient,
+        );
+        reload.live("slippage_rules", &mut self.slippage_rules, slippage_rules);
+        reload.live(
+            "node_failure_threshold",
+            &mut self.node_failure_threshold,
+            node_failure_threshold,
+        );
+        reload.live(
+            "max_backoff_secs",
+            &mut self.max_backoff_secs,
+            max_backoff_secs,
+        );
+        reload.live(
+            "give_up_after_secs",
+            &mut self.give_up_after_secs,
+            give_up_after_secs,
+        );
+        reload.live(
+            "max_concurrent_swaps",
+            &mut self.max_concurrent_swaps,
+            max_concurrent_swaps,
+        );
+        reload.live(
+            "park_after_failures",
+            &mut self.park_after_failures,
+            park_after_failures,
+        );
+        reload.live(
+            "park_after_secs",
+            &mut self.park_after_secs,
+            park_after_secs,
+        );
+        reload.live(
+            "parked_recheck_secs",
+            &mut self.parked_recheck_secs,
+            parked_recheck_secs,
+        );
+        reload.live(
+            "max_order_retries",
+            &mut self.max_order_retries,
+            max_order_retries,
+        );
+        reload.live("max_fee_bps", &mut self.max_fee_bps, max_fee_bps);
+        reload.live(
+            "max_fee_bps_by_token",
+            &mut self.max_fee_bps_by_token,
+            max_fee_bps_by_token,
+        );
+        reload.live(
+            "fee_recheck_secs",
+            &mut self.fee_recheck_secs,
+            fee_recheck_secs,
+        );
+
+        reload
     }
+
+    /// The largest share of a swap's amount its fee may take, for swaps that
+    /// sell `token`.
+    pub fn max_fee_bps_for(&self, token: Address) -> u16 {
+        self.max_fee_bps_by_token
+            .get(&token)
+            .copied()
+            .unwrap_or(self.max_fee_bps)
+    }
+
+    /// How the price checker at `price_checker` lays out its data.
+    pub fn price_checker_kind(&self, price_checker: Address) -> PriceCheckerKind {
+        self.price_checker_kinds
+            .get(&price_checker)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.infura_api_key.is_none() && self.node_base_url.is_none() {
+            bail!("either `infura_api_key` or `node_base_url` must be set");
+        }
+        if self.node_base_url.is_none() && self.network.infura_network().is_none() {
+            bail!(
+                "Infura doesn't serve network `{}`, so `node_base_url` must be set",
+                self.network
+            );
+        }
+        if self.milkman_deployments.is_empty() {
+            bail!("`milkman_deployments` must not be empty");
+        }
+        for (i, deployment) in self.milkman_deployments.iter().enumerate() {
+            if self.milkman_deployments[..i]
+                .iter()
+                .any(|other| other.address == deployment.address)
+            {
+                bail!(
+                    "Milkman deployment {:#x} is listed more than once",
+                    deployment.address
+                );
+            }
+        }
+        if self.polling_frequency_secs == 0 {
+            bail!("`polling_frequency_secs` must be greater than 0");
+        }
+        if self.node_failure_threshold == 0 {
+            bail!("`node_failure_threshold` must be greater than 0");
+        }
+        if self.max_concurrent_swaps == 0 {
+            bail!("`max_concurrent_swaps` must be greater than 0");
+        }
+        if self.max_fee_bps > 10000 {
+            bail!(
+                "`max_fee_bps` must be at most 10000, got {}",
+                self.max_fee_bps
+            );
+        }
+        for (token, max_fee_bps) in &self.max_fee_bps_by_token {
+            if *max_fee_bps > 10000 {
+                bail!(
+                    "`max_fee_bps_by_token` must be at most 10000, got {} for {:#x}",
+                    max_fee_bps,
+                    token
+                );
+            }
+        }
+        if self.park_after_failures == 0 {
+            bail!("`park_after_failures` must be greater than 0");
+        }
+        if self.slippage_tolerance_bps > 10_000 {
+            bail!(
+                "`slippage_tolerance_bps` must be at most 10000, got {}",
+                self.slippage_tolerance_bps
+            );
+        }
+        if self.max_slippage_bps > 10_000 {
+            bail!(
+                "`max_slippage_bps` must be at most 10000, got {}",
+                self.max_slippage_bps
+            );
+        }
+        match (self.partner_fee_bps, self.partner_fee_recipient) {
+            (Some(bps), Some(_)) if bps > 10_000 => {
+                bail!("`partner_fee_bps` must be at most 10000, got {}", bps)
+            }
+            (Some(_), None) | (None, Some(_)) => {
+                bail!("`partner_fee_bps` and `partner_fee_recipient` must be set together")
+            }
+            _ => (),
+        }
+        // the orderbook doesn't take orders that are valid for longer than a year
+        if self.limit_order_validity_secs > 365 * 24 * 60 * 60 {
+            bail!(
+                "`limit_order_validity_secs` must be at most a year, got {}",
+                self.limit_order_validity_secs
+            );
+        }
+        for rule in &self.slippage_rules {
+            if rule.slippage_tolerance_bps > 10_000 {
+                bail!(
+                    "`slippage_rules` must be at most 10000 bps, got {}",
+                    rule.slippage_tolerance_bps
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Configuration {
+    /// A configuration for tests, for a mainnet-like network on localhost.
+    pub fn for_tests() -> Self {
+        Configuration {
+            infura_api_key: None,
+            network: Network::Mainnet,
+            milkman_deployments: vec![Address::random().into()],
+            hash_helper_address: Address::random(),
+            settlement_address: Address::random(),
+            state_helper_address: None,
+            starting_block_number: None,
+            polling_frequency_secs: 10,
+            node_base_url: Some("http://localhost:8545".to_string()),
+            cow_api_base_url: "http://localhost:8080".to_string(),
+            slippage_tolerance_bps: 50,
+            slippage_step_bps: 0,
+            max_slippage_bps: 500,
+            buy_amount_strategy: Default::default(),
+            price_checker_kinds: Default::default(),
+            limit_orders: false,
+            limit_order_validity_secs: 604800,
+            app_code: "milkman-bot".to_string(),
+            bot_instance: None,
+            referrer_address: None,
+            partner_fee_bps: None,
+            partner_fee_recipient: None,
+            slippage_rules: Vec::new(),
+            node_failure_threshold: 5,
+            max_backoff_secs: 60,
+            give_up_after_secs: None,
+            max_concurrent_swaps: 8,
+            park_after_failures: 20,
+            park_after_secs: None,
+            parked_recheck_secs: 3600,
+            max_order_retries: 2,
+            max_fee_bps: 10000,
+            max_fee_bps_by_token: Default::default(),
+            fee_recheck_secs: 600,
+        }
+    }
+}
+
+/// Parse `<address>:<value>` pairs, separated by commas.
+fn parse_address_map<T>(value: &str) -> Result<HashMap<Address, T>>
+where
+    T: FromStr,
+    T::Err: Into<anyhow::Error>,
+{
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (address, value) = entry
+                .split_once(':')
+                .with_context(|| format!("expected `<address>:<value>`, got `{}`", entry))?;
+            Ok((
+                address
+                    .parse()
+                    .with_context(|| format!("invalid address `{}`", address))?,
+                value
+                    .parse()
+                    .map_err(Into::into)
+                    .with_context(|| format!("invalid value `{}`", value))?,
+            ))
+        })
+        .collect()
+}
+
+fn no_default(network: &Network, setting: &str) -> String {
+    format!(
+        "network `{}` has no default `{}`, so it must be set",
+        network, setting
+    )
+}
+
+fn parse_optional_variable<T>(
+    get_variable: impl Fn(&str) -> Result<Option<String>>,
+    key: &str,
+) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Into<anyhow::Error>,
+{
+    get_variable(key)?
+        .map(|value| value.parse::<T>().map_err(Into::into))
+        .transpose()
+        .with_context(|| format!("unable to parse environment variable {}", key))
 }
 
 fn collect_optional_environment_variable(key: &str) -> Result<Option<String>> {
@@ -89,8 +955,7 @@ mod tests {
     #[test]
     fn test_get_config() {
         setup_env_vars(Some("a"), Some("c"));
-        let config = Configuration::get_from_environment().expect("failed to get");
-        check_configuration(config, Some("a"), None);
+        assert!(Configuration::get_from_environment().is_err());
 
         setup_env_vars(Some("a"), Some("123"));
         let config = Configuration::get_from_environment().expect("failed to get");
@@ -104,6 +969,280 @@ mod tests {
         check_configuration(config, Some("a"), None);
     }
 
+    #[test]
+    fn test_get_config_from_file() {
+        let file = ConfigurationFile::parse(
+            r#"
+            network = "sepolia"
+            slippage_tolerance_bps = 100
+
+            [networks.mainnet]
+            infura_api_key = "mainnet-key"
+
+            [networks.sepolia]
+            node_base_url = "http://localhost:8545"
+            milkman_deployments = [
+                "0x11C76AD590ABDFFCD980afEC9ad951B160F02797",
+                { address = "0x5D9C7CBeF995ef16416D963EaCEEC8FcA2590731", version = "v1" },
+            ]
+            hash_helper_address = "0x49Fc95c908902Cf48f5F26ed5ADE284de3b55197"
+            starting_block_number = 42
+            slippage_tolerance_bps = 25
+            max_fee_bps = 500
+            buy_amount_strategy = "checker_midpoint"
+            limit_orders = true
+
+            [networks.sepolia.max_fee_bps_by_token]
+            "0x6B175474E89094C44Da98b954EedeAC495271d0F" = 100
+
+            [networks.sepolia.price_checker_kinds]
+            "0x6B175474E89094C44Da98b954EedeAC495271d0F" = "dynamic_slippage"
+
+            [[networks.sepolia.slippage_rules]]
+            name = "stables"
+            token = "0x6B175474E89094C44Da98b954EedeAC495271d0F"
+            slippage_tolerance_bps = 5
+            "#,
+        )
+        .expect("failed to parse");
+
+        let no_variables = |_: &str| Ok(None);
+        let config = Configuration::resolve(&file, no_variables).expect("failed to resolve");
+        assert_eq!(config.network, Network::Sepolia);
+        assert_eq!(
+            config.node_base_url.as_deref(),
+            Some("http://localhost:8545")
+        );
+        assert_eq!(config.infura_api_key, None);
+        assert_eq!(config.milkman_deployments.len(), 2);
+        assert_eq!(config.starting_block_number, Some(42));
+        assert_eq!(config.slippage_tolerance_bps, 25);
+        assert_eq!(config.polling_frequency_secs, 10);
+        let dai: Address = "0x6B175474E89094C44Da98b954EedeAC495271d0F"
+            .parse()
+            .unwrap();
+        assert_eq!(config.max_fee_bps_for(dai), 100);
+        assert_eq!(config.max_fee_bps_for(Address::zero()), 500);
+        assert_eq!(config.slippage_rules.len(), 1);
+        assert_eq!(config.slippage_rules[0].token, Some(dai));
+        assert_eq!(
+            config.buy_amount_strategy,
+            BuyAmountStrategy::CheckerMidpoint
+        );
+        assert!(config.limit_orders);
+        assert_eq!(
+            config.price_checker_kind(dai),
+            PriceCheckerKind::DynamicSlippage
+        );
+        assert_eq!(
+            config.price_checker_kind(Address::zero()),
+            PriceCheckerKind::Fixed
+        );
+        assert_eq!(config.limit_order_validity_secs, 604800);
+
+        let file = ConfigurationFile::parse("network = \"sepolia\"\n[networks.mainnet]\ninfura_api_key = \"k\"\nslippage_tolerance_bps = 25").unwrap();
+        let variables = |key: &str| {
+            Ok(match key {
+                "SLIPPAGE_TOLERANCE_BPS" => Some("75".to_string()),
+                "MILKMAN_NETWORK" => Some("mainnet".to_string()),
+                "MILKMAN_ADDRESS" => Some(
+                    "0x11C76AD590ABDFFCD980afEC9ad951B160F02797, 0x5D9C7CBeF995ef16416D963EaCEEC8FcA2590731"
+                        .to_string(),
+                ),
+                "MAX_FEE_BPS_BY_TOKEN" => {
+                    Some("0x6B175474E89094C44Da98b954EedeAC495271d0F:50".to_string())
+                }
+                "PRICE_CHECKER_KINDS" => {
+                    Some("0x6B175474E89094C44Da98b954EedeAC495271d0F:fixed".to_string())
+                }
+                _ => None,
+            })
+        };
+        let config = Configuration::resolve(&file, variables).expect("failed to resolve");
+        assert_eq!(config.network, Network::Mainnet);
+        assert_eq!(config.slippage_tolerance_bps, 75);
+        assert_eq!(config.milkman_deployments.len(), 2);
+        assert_eq!(config.cow_api_base_url, "https://api.cow.fi/mainnet");
+        assert_eq!(config.max_fee_bps_for(dai), 50);
+        assert_eq!(config.max_fee_bps_for(Address::zero()), 10000);
+        assert_eq!(config.buy_amount_strategy, BuyAmountStrategy::Slippage);
+        assert_eq!(config.price_checker_kind(dai), PriceCheckerKind::Fixed);
+
+        // networks without default addresses need them to be configured
+        let file = ConfigurationFile::parse("[networks.sepolia]\ninfura_api_key = \"k\"").unwrap();
+        let variables = |key: &str| Ok((key == "MILKMAN_NETWORK").then(|| "sepolia".to_string()));
+        assert!(Configuration::resolve(&file, variables).is_err());
+
+        // a partner fee needs someone to go to
+        let file = ConfigurationFile::parse("network = \"mainnet\"\npartner_fee_bps = 10").unwrap();
+        let variables = |key: &str| Ok((key == "INFURA_API_KEY").then(|| "k".to_string()));
+        assert!(Configuration::resolve(&file, variables).is_err());
+
+        assert!(ConfigurationFile::parse("unknown_setting = 1").is_err());
+        assert!(ConfigurationFile::parse(
+            "[networks.mainnet]\nmilkman_deployments = [{ address = \"0x12\" }]"
+        )
+        .is_err());
+
+        let file = ConfigurationFile::parse("[networks.mainnet]\ninfura_api_key = \"k\"").unwrap();
+        let variables = |key: &str| Ok((key == "MILKMAN_NETWORK").then(|| "gnosis".to_string()));
+        assert!(Configuration::resolve(&file, variables).is_err());
+
+        let file = ConfigurationFile::parse(
+            "slippage_tolerance_bps = 10001\n[networks.mainnet]\ninfura_api_key = \"k\"",
+        )
+        .unwrap();
+        assert!(Configuration::resolve(&file, no_variables).is_err());
+
+        let variables = |key: &str| {
+            Ok(match key {
+                "INFURA_API_KEY" => Some("k".to_string()),
+                "SLIPPAGE_TOLERANCE_BPS" => Some("fifty".to_string()),
+                _ => None,
+            })
+        };
+        assert!(Configuration::resolve(&ConfigurationFile::default(), variables).is_err());
+    }
+
+    #[test]
+    fn test_get_config_for_several_networks() {
+        let file = ConfigurationFile::parse(
+            r#"
+            metrics_port = 9090
+            state_dir = "/var/lib/milkman-bot"
+            polling_frequency_secs = 20
+
+            [max_fee_bps_by_token]
+            "0x6B175474E89094C44Da98b954EedeAC495271d0F" = 100
+            "0xA0b86991c6218b36c1d19D4a2E9Eb0cE3606eB48" = 200
+
+            [[slippage_rules]]
+            name = "long tail"
+            slippage_tolerance_bps = 300
+
+            [networks.mainnet]
+            infura_api_key = "mainnet-key"
+
+            [networks.mainnet.max_fee_bps_by_token]
+            "0x6B175474E89094C44Da98b954EedeAC495271d0F" = 50
+
+            [[networks.mainnet.slippage_rules]]
+            name = "stables"
+            token = "0x6B175474E89094C44Da98b954EedeAC495271d0F"
+            slippage_tolerance_bps = 5
+
+            [networks.devnet]
+            chain_id = 31337
+            node_base_url = "http://localhost:8545"
+            cow_api_base_url = "http://localhost:8080"
+            milkman_deployments = ["0x11C76AD590ABDFFCD980afEC9ad951B160F02797"]
+            hash_helper_address = "0x49Fc95c908902Cf48f5F26ed5ADE284de3b55197"
+            settlement_address = "0x5FbDB2315678afecb367f032d93F642f64180aa3"
+            polling_frequency_secs = 1
+            "#,
+        )
+        .expect("failed to parse");
+
+        let no_variables = |_: &str| Ok(None);
+        let config = BotConfiguration::resolve(&file, no_variables).expect("failed to resolve");
+        assert_eq!(config.metrics_port, Some(9090));
+        assert_eq!(
+            config.state_dir,
+            Some(PathBuf::from("/var/lib/milkman-bot"))
+        );
+        assert_eq!(config.shutdown_timeout_secs, 20);
+        assert!(!config.exit_on_give_up);
+        assert_eq!(config.networks.len(), 2);
+        assert_eq!(config.networks[0].network.chain_id(), 31337);
+        assert_eq!(config.networks[0].polling_frequency_secs, 1);
+        assert_eq!(
+            config.networks[0].settlement_address,
+            "0x5FbDB2315678afecb367f032d93F642f64180aa3"
+                .parse()
+                .unwrap()
+        );
+        assert_eq!(
+            Some(config.networks[1].settlement_address),
+            Network::Mainnet.default_settlement_address()
+        );
+        assert_eq!(config.networks[1].network, Network::Mainnet);
+        assert_eq!(config.networks[1].polling_frequency_secs, 20);
+
+        // settings of a single network can't come from the environment
+        let variables = |key: &str| {
+            Ok((key == "MILKMAN_ADDRESS")
+                .then(|| "0x11C76AD590ABDFFCD980afEC9ad951B160F02797".to_string()))
+        };
+        assert!(BotConfiguration::resolve(&file, variables).is_err());
+        let variables = |key: &str| Ok((key == "SLIPPAGE_TOLERANCE_BPS").then(|| "10".to_string()));
+        assert!(BotConfiguration::resolve(&file, variables).is_ok());
+
+        // top-level fee limits apply to every network, and a network's own win
+        let (dai, usdc): (Address, Address) = (
+            "0x6B175474E89094C44Da98b954EedeAC495271d0F"
+                .parse()
+                .unwrap(),
+            "0xA0b86991c6218b36c1d19D4a2E9Eb0cE3606eB48"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(config.networks[0].max_fee_bps_for(dai), 100);
+        assert_eq!(config.networks[1].max_fee_bps_for(dai), 50);
+        assert_eq!(config.networks[1].max_fee_bps_for(usdc), 200);
+
+        // top-level rules apply to every network, after the network's own
+        let rule_names = |config: &Configuration| -> Vec<Option<String>> {
+            config
+                .slippage_rules
+                .iter()
+                .map(|rule| rule.name.clone())
+                .collect()
+        };
+        assert_eq!(
+            rule_names(&config.networks[0]),
+            vec![Some("long tail".to_string())]
+        );
+        assert_eq!(
+            rule_names(&config.networks[1]),
+            vec![Some("stables".to_string()), Some("long tail".to_string())]
+        );
+
+        // single-network commands need the network to be picked
+        assert!(Configuration::resolve(&file, no_variables).is_err());
+
+        let variables = |key: &str| Ok((key == "MILKMAN_NETWORK").then(|| "mainnet".to_string()));
+        let config = BotConfiguration::resolve(&file, variables).expect("failed to resolve");
+        assert_eq!(config.networks.len(), 1);
+        assert!(Configuration::resolve(&file, variables).is_ok());
+
+        let variables =
+            |key: &str| Ok((key == "MILKMAN_NETWORK").then(|| "mainnet,mainnet".to_string()));
+        assert!(BotConfiguration::resolve(&file, variables).is_err());
+    }
+
+    #[test]
+    fn test_reload_config() {
+        let variables = |key: &str| {
+            Ok(match key {
+                "INFURA_API_KEY" => Some("k".to_string()),
+                _ => None,
+            })
+        };
+        let mut config = Configuration::resolve(&ConfigurationFile::default(), variables).unwrap();
+
+        let mut reloaded = config.clone();
+        reloaded.slippage_tolerance_bps = 10;
+        reloaded.hash_helper_address = Address::random();
+        let reload = config.reload(reloaded.clone());
+
+        assert_eq!(reload.applied, vec!["slippage_tolerance_bps"]);
+        assert_eq!(reload.needs_restart, vec!["hash_helper_address"]);
+        assert_eq!(config.slippage_tolerance_bps, 10);
+        assert_ne!(config.hash_helper_address, reloaded.hash_helper_address);
+
+        assert_eq!(config.clone().reload(config.clone()), Reload::default());
+    }
+
     fn setup_env_vars(infura_api_key: Option<&str>, starting_block_number: Option<&str>) {
         fn setup_env_var(key: &str, value: Option<&str>) {
             match value {
@@ -1,6 +1,2 @@
-pub const APP_DATA: &str = "2B8694ED30082129598720860E8E972F07AA10D9B81CAE16CA0E2CFB24743E24";
 pub const ERC20_BALANCE: &str = "5a28e9363bb942b639270062aa6bb295f434bcdfc42c97267bf003f272060dc9";
 pub const KIND_SELL: &str = "f3b277728b3fee749481eb3e0b3b48980dbbab78658fc419025cb16eee346775";
-
-pub const PROD_MILKMAN_ADDRESS: &str = "0x11C76AD590ABDFFCD980afEC9ad951B160F02797";
-pub const MAINNET_HASH_HELPER_ADDRESS: &str = "0x49Fc95c908902Cf48f5F26ed5ADE284de3b55197";
@@ -1,168 +1,606 @@
 use crate::configuration::Configuration;
-use anyhow::{anyhow, Context, Result};
+use anyhow::Result;
 use ethers::abi::Address;
 use ethers::types::{Bytes, U256};
 use log::{debug, info};
-use serde_json::Value;
+use reqwest::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::fmt;
 
-use crate::constants::APP_DATA;
+use crate::app_data::AppData;
+use crate::types::Swap;
 
-#[derive(Debug)]
-pub struct Quote {
-    pub fee_amount: U256,
-    pub buy_amount_after_fee: U256,
-    pub valid_to: u64,
-    pub id: u64,
+// The orderbook API's models, as in its OpenAPI spec. Only the fields the bot
+// uses are modelled, and unknown fields are ignored, so that additions to the
+// API don't break the bot. Amounts are decimal strings, parsed into `U256`.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OrderKind {
+    Buy,
+    Sell,
 }
 
-#[derive(Debug)]
-pub struct Order<'a> {
-    pub order_contract: Address,
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QuoteRequest {
     pub sell_token: Address,
     pub buy_token: Address,
+    pub receiver: Option<Address>,
+    pub app_data: String,
+    pub partially_fillable: bool,
+    pub sell_token_balance: String,
+    pub buy_token_balance: String,
+    pub from: Address,
+    pub price_quality: String,
+    pub signing_scheme: String,
+    pub onchain_order: bool,
+    pub verification_gas_limit: u64,
+    pub kind: OrderKind,
+    #[serde(with = "u256_decimal")]
+    pub sell_amount_before_fee: U256,
+}
+
+/// The order a quote is for, with the fee taken out of `sell_amount` and
+/// `buy_amount` (so a sell quote's `buy_amount` is after the fee).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QuotedOrder {
+    pub sell_token: Address,
+    pub buy_token: Address,
+    pub receiver: Option<Address>,
+    #[serde(with = "u256_decimal")]
     pub sell_amount: U256,
+    #[serde(with = "u256_decimal")]
     pub buy_amount: U256,
     pub valid_to: u64,
+    pub app_data: String,
+    #[serde(with = "u256_decimal")]
     pub fee_amount: U256,
+    pub kind: OrderKind,
+    pub partially_fillable: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Quote {
+    pub quote: QuotedOrder,
+    pub from: Address,
+    pub expiration: String,
+    pub id: Option<i64>,
+    #[serde(default)]
+    pub verified: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderCreation {
+    pub sell_token: Address,
+    pub buy_token: Address,
     pub receiver: Address,
-    pub eip_1271_signature: &'a Bytes,
-    pub quote_id: u64,
+    #[serde(with = "u256_decimal")]
+    pub sell_amount: U256,
+    #[serde(with = "u256_decimal")]
+    pub buy_amount: U256,
+    pub valid_to: u64,
+    pub app_data: String,
+    #[serde(with = "u256_decimal")]
+    pub fee_amount: U256,
+    pub kind: OrderKind,
+    pub partially_fillable: bool,
+    pub sell_token_balance: String,
+    pub buy_token_balance: String,
+    pub signing_scheme: String,
+    pub signature: Bytes,
+    pub from: Address,
+    pub quote_id: Option<i64>,
+}
+
+impl OrderCreation {
+    /// An EIP-1271 sell order from a Milkman order contract.
+    pub fn eip_1271_sell(
+        order_contract: Address,
+        quote: &Quote,
+        app_data: &AppData,
+        receiver: Address,
+        sell_amount: U256,
+        buy_amount: U256,
+        eip_1271_signature: Bytes,
+    ) -> Self {
+        Self {
+            sell_token: quote.quote.sell_token,
+            buy_token: quote.quote.buy_token,
+            receiver,
+            sell_amount,
+            buy_amount,
+            valid_to: quote.quote.valid_to,
+            app_data: app_data.hash_hex(),
+            fee_amount: quote.quote.fee_amount,
+            kind: OrderKind::Sell,
+            partially_fillable: false,
+            sell_token_balance: "erc20".to_string(),
+            buy_token_balance: "erc20".to_string(),
+            signing_scheme: "eip1271".to_string(),
+            signature: eip_1271_signature,
+            from: order_contract,
+            quote_id: quote.id,
+        }
+    }
+
+    /// An EIP-1271 sell limit order from a Milkman order contract. Limit orders
+    /// aren't based on a quote and pay no fee up front, so all of `sell_amount`
+    /// is sold.
+    pub fn eip_1271_limit_sell(
+        swap: &Swap,
+        app_data: &AppData,
+        buy_amount: U256,
+        valid_to: u64,
+        eip_1271_signature: Bytes,
+    ) -> Self {
+        Self {
+            sell_token: swap.from_token,
+            buy_token: swap.to_token,
+            receiver: swap.receiver,
+            sell_amount: swap.amount_in,
+            buy_amount,
+            valid_to,
+            app_data: app_data.hash_hex(),
+            fee_amount: U256::zero(),
+            kind: OrderKind::Sell,
+            partially_fillable: false,
+            sell_token_balance: "erc20".to_string(),
+            buy_token_balance: "erc20".to_string(),
+            signing_scheme: "eip1271".to_string(),
+            signature: eip_1271_signature,
+            from: swap.order_contract,
+            quote_id: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OrderStatus {
+    PresignaturePending,
+    Open,
+    Fulfilled,
+    Cancelled,
+    Expired,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OrderSummary {
+    status: OrderStatus,
+}
+
+/// An error response from the orderbook, classified by its `errorType`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CowApiError {
+    NoLiquidity(String),
+    UnsupportedToken(String),
+    InsufficientBalance(String),
+    SellAmountDoesNotCoverFee {
+        description: String,
+        fee_amount: Option<U256>, // from the error's data, if it has it
+    },
+    QuoteNotFound(String),
+    QuoteExpired(String),
+    InsufficientFee(String),
+    DuplicateOrder(String),
+    InvalidEip1271Signature(String),
+    TooManyLimitOrders(String),
+    RateLimited,
+    ServerError {
+        status: u16,
+        body: String,
+    },
+    Other {
+        status: u16,
+        error_type: Option<String>,
+        description: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ErrorBody {
+    error_type: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    data: Option<serde_json::Value>,
+}
+
+impl CowApiError {
+    pub fn from_response(status: StatusCode, body: &str) -> Self {
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return Self::RateLimited;
+        }
+        if status.is_server_error() {
+            return Self::ServerError {
+                status: status.as_u16(),
+                body: body.to_string(),
+            };
+        }
+
+        let ErrorBody {
+            error_type,
+            description,
+            data,
+        } = match serde_json::from_str(body) {
+            Ok(error_body) => error_body,
+            Err(_) => {
+                return Self::Other {
+                    status: status.as_u16(),
+                    error_type: None,
+                    description: body.to_string(),
+                }
+            }
+        };
+        match error_type.as_str() {
+            "NoLiquidity" => Self::NoLiquidity(description),
+            "UnsupportedToken" => Self::UnsupportedToken(description),
+            "InsufficientBalance" => Self::InsufficientBalance(description),
+            "SellAmountDoesNotCoverFee" => Self::SellAmountDoesNotCoverFee {
+                description,
+                fee_amount: data.as_ref().and_then(fee_amount),
+            },
+            "QuoteNotFound" => Self::QuoteNotFound(description),
+            "QuoteExpired" | "InsufficientValidTo" => Self::QuoteExpired(description),
+            "InsufficientFee" => Self::InsufficientFee(description),
+            "DuplicateOrder" | "DuplicatedOrder" => Self::DuplicateOrder(description),
+            "InvalidEip1271Signature" => Self::InvalidEip1271Signature(description),
+            "TooManyLimitOrders" => Self::TooManyLimitOrders(description),
+            _ => Self::Other {
+                status: status.as_u16(),
+                error_type: Some(error_type),
+                description,
+            },
+        }
+    }
+
+    /// Why the order was rejected, if a fresh quote might get it accepted.
+    pub fn requote_reason(&self) -> Option<&'static str> {
+        match self {
+            Self::QuoteNotFound(_) => Some("quote_not_found"),
+            Self::QuoteExpired(_) => Some("quote_expired"),
+            Self::InsufficientFee(_) => Some("insufficient_fee"),
+            _ => None,
+        }
+    }
+
+    /// Read an unsuccessful response's body into an error.
+    async fn from_failed_response(response: Response) -> anyhow::Error {
+        let status = response.status();
+        match response.text().await {
+            Ok(body) => {
+                debug!("request failed with status {} and body: {:?}", status, body);
+                Self::from_response(status, &body).into()
+            }
+            Err(err) => err.into(),
+        }
+    }
+}
+
+impl fmt::Display for CowApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoLiquidity(description) => write!(f, "no liquidity: {}", description),
+            Self::UnsupportedToken(description) => write!(f, "unsupported token: {}", description),
+            Self::InsufficientBalance(description) => {
+                write!(f, "insufficient balance: {}", description)
+            }
+            Self::SellAmountDoesNotCoverFee {
+                description,
+                fee_amount: Some(fee_amount),
+            } => write!(
+                f,
+                "sell amount doesn't cover the fee of {}: {}",
+                fee_amount, description
+            ),
+            Self::SellAmountDoesNotCoverFee { description, .. } => {
+                write!(f, "sell amount doesn't cover the fee: {}", description)
+            }
+            Self::QuoteNotFound(description) => write!(f, "quote not found: {}", description),
+            Self::QuoteExpired(description) => write!(f, "quote expired: {}", description),
+            Self::InsufficientFee(description) => write!(f, "insufficient fee: {}", description),
+            Self::DuplicateOrder(description) => write!(f, "duplicate order: {}", description),
+            Self::InvalidEip1271Signature(description) => {
+                write!(f, "invalid EIP-1271 signature: {}", description)
+            }
+            Self::TooManyLimitOrders(description) => {
+                write!(f, "too many limit orders: {}", description)
+            }
+            Self::RateLimited => write!(f, "rate limited by the orderbook"),
+            Self::ServerError { status, body } => {
+                write!(f, "orderbook server error ({}): {}", status, body)
+            }
+            Self::Other {
+                status,
+                error_type,
+                description,
+            } => match error_type {
+                Some(error_type) => write!(f, "{} ({}): {}", error_type, status, description),
+                None => write!(f, "orderbook error ({}): {}", status, description),
+            },
+        }
+    }
+}
+
+impl std::error::Error for CowApiError {}
+
+/// The fee in an error's `data`, which the orderbook gives as a hex string.
+fn fee_amount(data: &serde_json::Value) -> Option<U256> {
+    let fee_amount = data.get("fee_amount")?.as_str()?;
+    match fee_amount.strip_prefix("0x") {
+        Some(hex) => U256::from_str_radix(hex, 16).ok(),
+        None => U256::from_dec_str(fee_amount).ok(),
+    }
 }
 
 pub struct CowAPIClient {
     pub base_url: String,
-    pub milkman_address: String,
 }
 
 impl CowAPIClient {
     pub fn new(config: &Configuration) -> Self {
         Self {
-            base_url: format!("https://api.cow.fi/{}/api/v1/", config.network),
-            milkman_address: config.milkman_address.to_string(),
+            base_url: format!("{}/api/v1/", config.cow_api_base_url.trim_end_matches('/')),
         }
     }
 
+    /// Fetch the orderbook's version, which doubles as a reachability check.
+    pub async fn get_version(&self) -> Result<String> {
+        Ok(reqwest::Client::new()
+            .get(self.base_url.clone() + "version")
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?)
+    }
+
+    /// The status of an order, e.g. `open`, `fulfilled`, `cancelled` or `expired`.
+    pub async fn get_order_status(&self, order_uid: &str) -> Result<OrderStatus> {
+        let order = reqwest::Client::new()
+            .get(self.base_url.clone() + "orders/" + order_uid)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<OrderSummary>()
+            .await?;
+
+        Ok(order.status)
+    }
+
+    /// Quote selling `sell_amount_before_fee` from a Milkman order contract.
     pub async fn get_quote(
         &self,
         order_contract: Address,
         sell_token: Address,
         buy_token: Address,
         sell_amount_before_fee: U256,
+        app_data: &AppData,
         verification_gas_limit: u64,
     ) -> Result<Quote> {
         let http_client = reqwest::Client::new();
 
         let response = http_client
             .post(self.base_url.clone() + "quote")
-            .json(&serde_json::json!({
-                "sellToken": sell_token,
-                "buyToken": buy_token,
-                "sellAmountBeforeFee": sell_amount_before_fee.to_string(),
-                "appData": "0x".to_string() + APP_DATA,
-                "kind": "sell",
-                "partiallyFillable": false,
-                "from": order_contract,
-                "sellTokenBalance": "erc20",
-                "buyTokenBalance": "erc20",
-                "signingScheme": "eip1271",
-                "onchainOrder": true,
-                "priceQuality": "optimal",
-                "verificationGasLimit": verification_gas_limit,
-            }))
+            .json(&QuoteRequest {
+                sell_token,
+                buy_token,
+                receiver: None,
+                app_data: app_data.hash_hex(),
+                partially_fillable: false,
+                sell_token_balance: "erc20".to_string(),
+                buy_token_balance: "erc20".to_string(),
+                from: order_contract,
+                price_quality: "optimal".to_string(),
+                signing_scheme: "eip1271".to_string(),
+                onchain_order: true,
+                verification_gas_limit,
+                kind: OrderKind::Sell,
+                sell_amount_before_fee,
+            })
             .send()
             .await?;
 
-        let response_body = match response.error_for_status_ref() {
-            Ok(_) => response.json::<Value>().await?,
-            Err(err) => {
-                debug!("GET quote failed with body: {:?}", response.text().await?);
-                return Err(anyhow!(err));
-            }
-        };
+        if !response.status().is_success() {
+            return Err(CowApiError::from_failed_response(response).await);
+        }
+        let quote = response.json::<Quote>().await?;
 
-        debug!(
-            "Got back the following response body from the quote endpoint: {:?}",
-            response_body
-        );
+        debug!("Got back the following quote: {:?}", quote);
 
-        let quote = &response_body["quote"];
-        let fee_amount = quote["feeAmount"]
-            .as_str()
-            .context("unable to get `feeAmount` on quote")?
-            .to_owned();
-        let buy_amount_after_fee = quote["buyAmount"]
-            .as_str()
-            .context("unable to get `buyAmountAfterFee` from quote")?
-            .to_owned();
-        let valid_to = quote["validTo"]
-            .as_u64()
-            .context("unable to get `validTo` from quote")?;
-        let id = response_body["id"]
-            .as_u64()
-            .context("unable to get `id` from quote")?;
-
-        Ok(Quote {
-            fee_amount: fee_amount.parse::<u128>()?.into(),
-            buy_amount_after_fee: buy_amount_after_fee.parse::<u128>()?.into(),
-            valid_to,
-            id,
-        })
+        Ok(quote)
     }
 
-    pub async fn create_order(
-        &self,
-        Order {
-            order_contract,
-            sell_token,
-            buy_token,
-            sell_amount,
-            buy_amount,
-            valid_to,
-            fee_amount,
-            receiver,
-            eip_1271_signature,
-            quote_id,
-        }: Order<'_>,
-    ) -> Result<String> {
+    /// Upload an appData document, so that the orderbook knows what its hash
+    /// stands for. Uploading a document again is fine.
+    pub async fn upload_app_data(&self, app_data: &AppData) -> Result<()> {
+        let response = reqwest::Client::new()
+            .put(self.base_url.clone() + "app_data/" + &app_data.hash_hex())
+            .json(&serde_json::json!({ "fullAppData": app_data.document }))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(CowApiError::from_failed_response(response).await);
+        }
+        debug!("uploaded appData {}", app_data.hash_hex());
+
+        Ok(())
+    }
+
+    pub async fn create_order(&self, order: &OrderCreation) -> Result<String> {
         let http_client = reqwest::Client::new();
         let response = http_client
             .post(self.base_url.clone() + "orders")
-            .json(&serde_json::json!({
-                "sellToken": sell_token,
-                "buyToken": buy_token,
-                "sellAmount": sell_amount.to_string(),
-                "buyAmount": buy_amount.to_string(),
-                "validTo": valid_to,
-                "appData": "0x2B8694ED30082129598720860E8E972F07AA10D9B81CAE16CA0E2CFB24743E24",
-                "feeAmount": fee_amount.to_string(),
-                "kind": "sell",
-                "partiallyFillable": false,
-                "receiver": receiver,
-                "signature": eip_1271_signature.to_string(),
-                "from": order_contract,
-                "sellTokenBalance": "erc20",
-                "buyTokenBalance": "erc20",
-                "signingScheme": "eip1271",
-                "quoteId": quote_id,
-            }))
+            .json(order)
             .send()
             .await?;
 
-        let order_uid = match response.error_for_status_ref() {
-            Ok(_) => response
-                .json::<Value>()
-                .await?
-                .as_str()
-                .context("Unable to retrieve UID from POST order response")?
-                .to_string(),
-            Err(err) => {
-                debug!("POST order failed with body: {:?}", response.text().await?);
-                return Err(anyhow!(err));
-            }
-        };
+        if !response.status().is_success() {
+            return Err(CowApiError::from_failed_response(response).await);
+        }
+        let order_uid = response.json::<String>().await?;
 
         info!("created order with UID {}", order_uid);
 
         Ok(order_uid)
     }
 }
+
+/// (De)serializes a `U256` as a decimal string, as the orderbook expects,
+/// rather than the hex string `U256` uses by default.
+mod u256_decimal {
+    use ethers::types::U256;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &U256, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&value.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<U256, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        U256::from_dec_str(&value).map_err(|err| {
+            de::Error::custom(format!("invalid decimal amount `{}` – {:?}", value, err))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::H256;
+    use ethers::utils::keccak256;
+
+    #[test]
+    fn test_parse_quote() {
+        // an amount that doesn't fit in a u128, and a field we don't know about
+        let quote: Quote = serde_json::from_value(serde_json::json!({
+            "quote": {
+                "sellToken": "0x6b175474e89094c44da98b954eedeac495271d0f",
+                "buyToken": "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2",
+                "receiver": null,
+                "sellAmount": "1000000000000000000000000000000000000000",
+                "buyAmount": "512000000000000000",
+                "validTo": 1700000000u64,
+                "appData": "0x2b8694ed30082129598720860e8e972f07aa10d9b81cae16ca0e2cfb24743e24",
+                "feeAmount": "2000000000000000",
+                "kind": "sell",
+                "partiallyFillable": false,
+                "sellTokenBalance": "erc20",
+                "buyTokenBalance": "erc20",
+                "signingScheme": "eip1271"
+            },
+            "from": "0x11c76ad590abdffcd980afec9ad951b160f02797",
+            "expiration": "2023-11-14T22:13:20.000Z",
+            "id": 12345,
+            "verified": true,
+            "somethingNew": [1, 2, 3]
+        }))
+        .unwrap();
+
+        assert_eq!(
+            quote.quote.sell_amount,
+            U256::from_dec_str("1000000000000000000000000000000000000000").unwrap()
+        );
+        assert_eq!(quote.quote.kind, OrderKind::Sell);
+        assert_eq!(quote.id, Some(12345));
+        assert!(quote.verified);
+
+        let app_data = AppData {
+            document: "{}".to_string(),
+            hash: H256(keccak256("{}")),
+        };
+        let order = OrderCreation::eip_1271_sell(
+            quote.from,
+            &quote,
+            &app_data,
+            Address::zero(),
+            quote.quote.sell_amount,
+            quote.quote.buy_amount,
+            Bytes::from(vec![0x12, 0x34]),
+        );
+        let order = serde_json::to_value(&order).unwrap();
+        assert_eq!(
+            order["sellAmount"],
+            "1000000000000000000000000000000000000000"
+        );
+        assert_eq!(order["feeAmount"], "2000000000000000");
+        assert_eq!(order["signature"], "0x1234");
+        assert_eq!(order["quoteId"], 12345);
+        assert_eq!(order["appData"], app_data.hash_hex());
+    }
+
+    #[test]
+    fn test_classify_errors() {
+        assert_eq!(
+            CowApiError::from_response(
+                StatusCode::BAD_REQUEST,
+                r#"{"errorType":"NoLiquidity","description":"no route found"}"#
+            ),
+            CowApiError::NoLiquidity("no route found".to_string())
+        );
+        assert_eq!(
+            CowApiError::from_response(
+                StatusCode::BAD_REQUEST,
+                r#"{"errorType":"DuplicatedOrder","description":"order already exists"}"#
+            ),
+            CowApiError::DuplicateOrder("order already exists".to_string())
+        );
+        let expired = CowApiError::from_response(
+            StatusCode::BAD_REQUEST,
+            r#"{"errorType":"InsufficientValidTo","description":"validTo is too soon"}"#,
+        );
+        assert_eq!(expired.requote_reason(), Some("quote_expired"));
+        assert_eq!(
+            CowApiError::NoLiquidity(String::new()).requote_reason(),
+            None
+        );
+        assert_eq!(
+            CowApiError::from_response(
+                StatusCode::BAD_REQUEST,
+                r#"{"errorType":"SomethingNew","description":"?"}"#
+            ),
+            CowApiError::Other {
+                status: 400,
+                error_type: Some("SomethingNew".to_string()),
+                description: "?".to_string(),
+            }
+        );
+        assert_eq!(
+            CowApiError::from_response(
+                StatusCode::BAD_REQUEST,
+                r#"{"errorType":"SellAmountDoesNotCoverFee","description":"fee too high","data":{"fee_amount":"0x3e8"}}"#
+            ),
+            CowApiError::SellAmountDoesNotCoverFee {
+                description: "fee too high".to_string(),
+                fee_amount: Some(U256::from(1000)),
+            }
+        );
+        assert_eq!(
+            CowApiError::from_response(
+                StatusCode::BAD_REQUEST,
+                r#"{"errorType":"SellAmountDoesNotCoverFee","description":"fee too high"}"#
+            ),
+            CowApiError::SellAmountDoesNotCoverFee {
+                description: "fee too high".to_string(),
+                fee_amount: None,
+            }
+        );
+        assert_eq!(
+            CowApiError::from_response(StatusCode::TOO_MANY_REQUESTS, ""),
+            CowApiError::RateLimited
+        );
+        assert!(matches!(
+            CowApiError::from_response(StatusCode::BAD_GATEWAY, "<html></html>"),
+            CowApiError::ServerError { status: 502, .. }
+        ));
+    }
+}
@@ -2,7 +2,7 @@ use ethers::abi::Token;
 use ethers::prelude::*;
 use hex::FromHex;
 
-use crate::constants::{APP_DATA, ERC20_BALANCE, KIND_SELL};
+use crate::constants::{ERC20_BALANCE, KIND_SELL};
 
 #[derive(Debug)]
 pub struct SignatureData<'a> {
@@ -12,6 +12,7 @@ pub struct SignatureData<'a> {
     pub sell_amount_after_fees: U256,
     pub buy_amount_after_fees_and_slippage: U256,
     pub valid_to: u64,
+    pub app_data: H256, // the hash of the order's appData document
     pub fee_amount: U256,
     pub order_creator: Address,
     pub price_checker: Address,
@@ -26,6 +27,7 @@ pub fn get_eip_1271_signature(
         sell_amount_after_fees,
         buy_amount_after_fees_and_slippage,
         valid_to,
+        app_data,
         fee_amount,
         order_creator,
         price_checker,
@@ -39,7 +41,7 @@ pub fn get_eip_1271_signature(
         Token::Uint(sell_amount_after_fees),
         Token::Uint(buy_amount_after_fees_and_slippage),
         Token::Uint(valid_to.into()),
-        Token::FixedBytes(Vec::from_hex(APP_DATA).unwrap()),
+        Token::FixedBytes(app_data.as_bytes().to_vec()),
         Token::Uint(fee_amount),
         Token::FixedBytes(Vec::from_hex(KIND_SELL).unwrap()),
         Token::Bool(false), // partiallyFillable = false; this is fill or kill order
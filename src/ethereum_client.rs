@@ -2,15 +2,15 @@ use anyhow::{Context, Result};
 use ethers::prelude::*;
 use hex::FromHex;
 use log::debug;
-#[cfg(test)]
-use rand::prelude::*;
-use std::convert::{From, Into};
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
+use crate::buy_amount::PriceCheckerKind;
 use crate::configuration::Configuration;
-use crate::constants::{APP_DATA, ERC20_BALANCE, KIND_SELL};
-use crate::encoder::{self, SignatureData};
-use crate::types::{BlockNumber, Swap};
+use crate::constants::{ERC20_BALANCE, KIND_SELL};
+use crate::encoder::SignatureData;
+use crate::milkman_adapter::MilkmanDeployment;
+use crate::types::{BlockNumber, Swap, SwapProvenance};
 
 abigen!(
     RawMilkman,
@@ -30,13 +30,33 @@ abigen!(
     event_derives(serde::Deserialize, serde::Serialize),
 );
 
+abigen!(
+    RawPriceChecker,
+    "./abis/PriceChecker.json",
+    event_derives(serde::Deserialize, serde::Serialize),
+);
+
+abigen!(
+    RawExpectedOutCalculator,
+    "./abis/ExpectedOutCalculator.json",
+    event_derives(serde::Deserialize, serde::Serialize),
+);
+
 pub type Milkman = RawMilkman<Provider<Http>>;
 pub type HashHelper = RawHashHelper<Provider<Http>>;
 pub type ERC20 = RawERC20<Provider<Http>>;
+pub type PriceChecker = RawPriceChecker<Provider<Http>>;
+pub type ExpectedOutCalculator = RawExpectedOutCalculator<Provider<Http>>;
+
+/// A provider that signs transactions with a local key. Only used by the
+/// testing commands; the bot itself never sends transactions.
+pub type SigningProvider = SignerMiddleware<Provider<Http>, LocalWallet>;
 
 pub struct EthereumClient {
     inner_client: Arc<Provider<Http>>,
-    milkman: Milkman,
+    milkman_deployments: Vec<MilkmanDeployment>,
+    domain_separators: Mutex<HashMap<Address, [u8; 32]>>, // per Milkman deployment, they never change
+    block_timestamps: Mutex<HashMap<H256, u64>>, // of the blocks with swap requests in the last scan
 }
 
 impl EthereumClient {
@@ -46,15 +66,20 @@ impl EthereumClient {
         } else {
             format!(
                 "https://{}.infura.io/v3/{}",
-                config.network,
+                config
+                    .network
+                    .infura_network()
+                    .context("network isn't served by Infura")?,
                 config.infura_api_key.clone().unwrap()
             )
         };
         let provider = Arc::new(Provider::<Http>::try_from(node_url)?);
 
         Ok(Self {
-            milkman: Milkman::new(config.milkman_address, Arc::clone(&provider)),
             inner_client: provider,
+            milkman_deployments: config.milkman_deployments.clone(),
+            domain_separators: Mutex::new(HashMap::new()),
+            block_timestamps: Mutex::new(HashMap::new()),
         })
     }
 
@@ -66,6 +91,31 @@ impl EthereumClient {
             .map(|block_num: U64| block_num.try_into().unwrap()) // U64 -> u64 should always work
     }
 
+    pub async fn get_chain_id(&self) -> Result<u64> {
+        Ok(self.inner_client.get_chainid().await?.as_u64())
+    }
+
+    pub async fn has_code(&self, address: Address) -> Result<bool> {
+        Ok(!self.inner_client.get_code(address, None).await?.is_empty())
+    }
+
+    pub async fn get_domain_separator(&self, milkman: Address) -> Result<[u8; 32]> {
+        if let Some(domain_separator) = self.domain_separators.lock().unwrap().get(&milkman) {
+            return Ok(*domain_separator);
+        }
+
+        let domain_separator = Milkman::new(milkman, Arc::clone(&self.inner_client))
+            .domain_separator()
+            .call()
+            .await?;
+        self.domain_separators
+            .lock()
+            .unwrap()
+            .insert(milkman, domain_separator);
+
+        Ok(domain_separator)
+    }
+
     #[cfg(test)]
     pub async fn get_chain_timestamp(&self) -> Result<u64> {
         Ok(self.get_latest_block().await?.timestamp.as_u64())
@@ -78,21 +128,231 @@ impl EthereumClient {
             .context("Error fetching latest block.")
     }
 
+    /// Fetch the swaps requested from any of the Milkman deployments in a
+    /// single `eth_getLogs` call. Each deployment's adapter decodes its own logs.
+    /// Swaps come back in the order they were requested, along with the log
+    /// they were requested in.
     pub async fn get_requested_swaps(
         &self,
         from_block: BlockNumber,
         to_block: BlockNumber,
     ) -> Result<Vec<Swap>> {
-        Ok(self
-            .milkman
-            .swap_requested_filter()
+        let mut topics: Vec<H256> = self
+            .milkman_deployments
+            .iter()
+            .map(|deployment| deployment.adapter().swap_requested_topic())
+            .collect();
+        topics.dedup();
+
+        let filter = Filter::new()
             .from_block(from_block)
             .to_block(to_block)
-            .query()
-            .await?
+            .address(ValueOrArray::Array(
+                self.milkman_deployments
+                    .iter()
+                    .map(|deployment| deployment.address)
+                    .collect(),
+            ))
+            .topic0(ValueOrArray::Array(topics));
+
+        let logs = self.inner_client.get_logs(&filter).await?;
+
+        let mut block_timestamps = HashMap::new();
+        for block_hash in logs.iter().filter_map(|log| log.block_hash) {
+            if block_timestamps.contains_key(&block_hash) {
+                continue;
+            }
+            let cached = self
+                .block_timestamps
+                .lock()
+                .unwrap()
+                .get(&block_hash)
+                .copied();
+            let block_timestamp = match cached {
+                Some(block_timestamp) => block_timestamp,
+                None => self
+                    .inner_client
+                    .get_block(block_hash)
+                    .await?
+                    .with_context(|| format!("block {:#x} not found", block_hash))?
+                    .timestamp
+                    .as_u64(),
+            };
+            block_timestamps.insert(block_hash, block_timestamp);
+        }
+        // scans overlap by the lookback, so the next one mostly has the same
+        // blocks, and only needs the timestamps of the new ones
+        *self.block_timestamps.lock().unwrap() = block_timestamps.clone();
+
+        let mut swaps: Vec<Swap> = logs
+            .into_iter()
+            .filter_map(|log| {
+                let block_timestamp = log
+                    .block_hash
+                    .and_then(|block_hash| block_timestamps.get(&block_hash).copied());
+                match self.decode_swap_requested(log, block_timestamp) {
+                    Ok(swap) => swap,
+                    Err(err) => {
+                        tracing::error!("unable to decode `SwapRequested` log – {:?}", err);
+                        None
+                    }
+                }
+            })
+            .collect();
+        swaps.sort_by_key(|swap| swap.provenance.map(|provenance| provenance.position()));
+
+        Ok(swaps)
+    }
+
+    /// Decode a log emitted by one of the Milkman deployments, if it announces
+    /// a swap.
+    fn decode_swap_requested(
+        &self,
+        log: Log,
+        block_timestamp: Option<u64>,
+    ) -> Result<Option<Swap>> {
+        let deployment = match self
+            .milkman_deployments
             .iter()
-            .map(Into::into)
-            .collect())
+            .find(|deployment| deployment.address == log.address)
+        {
+            Some(deployment) => *deployment,
+            None => return Ok(None),
+        };
+        let adapter = deployment.adapter();
+        if log.topics.first() != Some(&adapter.swap_requested_topic()) {
+            return Ok(None);
+        }
+
+        let provenance = match (
+            log.transaction_hash,
+            log.block_number,
+            log.block_hash,
+            log.log_index,
+            block_timestamp,
+        ) {
+            (
+                Some(transaction_hash),
+                Some(block_number),
+                Some(block_hash),
+                Some(log_index),
+                Some(block_timestamp),
+            ) => Some(SwapProvenance {
+                transaction_hash,
+                block_number: block_number.as_u64(),
+                block_hash,
+                log_index: log_index.as_u64(),
+                block_timestamp,
+            }),
+            _ => None, // a pending log, or one from a receipt
+        };
+
+        let swap = adapter.decode_swap_requested(
+            deployment,
+            &abi::RawLog {
+                topics: log.topics,
+                data: log.data.to_vec(),
+            },
+        )?;
+        Ok(Some(Swap { provenance, ..swap }))
+    }
+
+    /// Check that the order contract committed to the swap as we decoded it,
+    /// which catches a deployment configured with the wrong version.
+    pub async fn verify_swap_hash(&self, swap: &Swap) -> Result<bool> {
+        let swap_hash = Milkman::new(swap.order_contract, Arc::clone(&self.inner_client))
+            .swap_hash()
+            .call()
+            .await?;
+
+        Ok(H256::from(swap_hash) == swap.milkman.adapter().swap_hash(swap))
+    }
+
+    /// Find the swap that created `order_contract`, which is needed to rebuild
+    /// the parameters that `cancelSwap` expects.
+    pub async fn find_requested_swap(
+        &self,
+        order_contract: Address,
+        from_block: BlockNumber,
+    ) -> Result<Swap> {
+        let to_block = self.get_latest_block_number().await?;
+
+        self.get_requested_swaps(from_block, to_block)
+            .await?
+            .into_iter()
+            .find(|swap| swap.order_contract == order_contract)
+            .with_context(|| {
+                format!(
+                    "no swap with order contract {:#x} requested between blocks {} and {}",
+                    order_contract, from_block, to_block
+                )
+            })
+    }
+
+    pub async fn get_signing_provider(&self, private_key: &str) -> Result<Arc<SigningProvider>> {
+        let chain_id = self.inner_client.get_chainid().await?;
+        let wallet = private_key
+            .trim_start_matches("0x")
+            .parse::<LocalWallet>()
+            .context("unable to parse private key")?
+            .with_chain_id(chain_id.as_u64());
+
+        Ok(Arc::new(SignerMiddleware::new(
+            (*self.inner_client).clone(),
+            wallet,
+        )))
+    }
+
+    /// Approve Milkman to pull `amount_in` and request a swap, returning the
+    /// order contract that was created for it.
+    pub async fn request_swap(&self, signer: Arc<SigningProvider>, swap: &Swap) -> Result<Address> {
+        let token = RawERC20::new(swap.from_token, Arc::clone(&signer));
+        token
+            .approve(swap.milkman.address, swap.amount_in)
+            .send()
+            .await?
+            .await?
+            .context("approval transaction was dropped")?;
+
+        let receipt = RawMilkman::new(swap.milkman.address, Arc::clone(&signer))
+            .request_swap_exact_tokens_for_tokens(
+                swap.amount_in,
+                swap.from_token,
+                swap.to_token,
+                swap.receiver,
+                swap.price_checker,
+                swap.price_checker_data.clone(),
+            )
+            .send()
+            .await?
+            .await?
+            .context("swap request transaction was dropped")?;
+
+        receipt
+            .logs
+            .into_iter()
+            .filter(|log| log.address == swap.milkman.address)
+            .find_map(|log| self.decode_swap_requested(log, None).ok().flatten())
+            .map(|requested_swap| requested_swap.order_contract)
+            .context("no `SwapRequested` event in the swap request receipt")
+    }
+
+    pub async fn cancel_swap(&self, signer: Arc<SigningProvider>, swap: &Swap) -> Result<H256> {
+        let receipt = RawMilkman::new(swap.order_contract, signer)
+            .cancel_swap(
+                swap.amount_in,
+                swap.from_token,
+                swap.to_token,
+                swap.receiver,
+                swap.price_checker,
+                swap.price_checker_data.clone(),
+            )
+            .send()
+            .await?
+            .await?
+            .context("cancel transaction was dropped")?;
+
+        Ok(receipt.transaction_hash)
     }
 
     pub async fn get_balance_of(&self, token_address: Address, user: Address) -> Result<U256> {
@@ -101,6 +361,56 @@ impl EthereumClient {
         Ok(token.balance_of(user).call().await?)
     }
 
+    /// Whether the swap's price checker accepts selling all of its amount, of
+    /// which `fee_amount` goes to the fee, for at least `min_out`. Price
+    /// checkers that revert rather than return `false` reject it too.
+    pub async fn check_price(&self, swap: &Swap, fee_amount: U256, min_out: U256) -> Result<bool> {
+        let price_checker = PriceChecker::new(swap.price_checker, Arc::clone(&self.inner_client));
+
+        match price_checker
+            .check_price(
+                swap.amount_in,
+                swap.from_token,
+                swap.to_token,
+                fee_amount,
+                min_out,
+                swap.price_checker_data.clone(),
+            )
+            .call()
+            .await
+        {
+            Ok(accepted) => Ok(accepted),
+            Err(err) if is_revert(&err) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// What the swap's price checker expects selling all of its amount to get,
+    /// according to the checker's `EXPECTED_OUT_CALCULATOR`, which gets the
+    /// part of the swap's price checker data that `kind` passes on.
+    pub async fn get_expected_out(&self, swap: &Swap, kind: PriceCheckerKind) -> Result<U256> {
+        let price_checker = PriceChecker::new(swap.price_checker, Arc::clone(&self.inner_client));
+        let calculator_address = price_checker
+            .expected_out_calculator()
+            .call()
+            .await
+            .context("price checker has no `EXPECTED_OUT_CALCULATOR`")?;
+        let calculator =
+            ExpectedOutCalculator::new(calculator_address, Arc::clone(&self.inner_client));
+
+        let calculator_data = kind.calculator_data(&swap.price_checker_data)?;
+
+        Ok(calculator
+            .get_expected_out(
+                swap.amount_in,
+                swap.from_token,
+                swap.to_token,
+                calculator_data,
+            )
+            .call()
+            .await?)
+    }
+
     /// To estimate the amount of gas it'll take to call `isValidSignature`, we
     /// create a mock order & signature based on the existing order and use those
     /// along with ethers-rs's `estimate_gas()`.
@@ -108,6 +418,7 @@ impl EthereumClient {
         &self,
         config: &Configuration,
         swap_request: &Swap,
+        app_data: H256,
     ) -> Result<U256> {
         let order_contract =
             Milkman::new(swap_request.order_contract, Arc::clone(&self.inner_client));
@@ -115,7 +426,9 @@ impl EthereumClient {
         let hash_helper =
             HashHelper::new(config.hash_helper_address, Arc::clone(&self.inner_client));
 
-        let domain_separator = self.milkman.domain_separator().call().await?;
+        let domain_separator = self
+            .get_domain_separator(swap_request.milkman.address)
+            .await?;
 
         let mock_order = Data {
             sell_token: swap_request.from_token,
@@ -124,7 +437,7 @@ impl EthereumClient {
             sell_amount: swap_request.amount_in,
             buy_amount: U256::MAX,
             valid_to: u32::MAX,
-            app_data: Vec::from_hex(APP_DATA).unwrap().try_into().unwrap(),
+            app_data: app_data.0,
             fee_amount: U256::zero(),
             kind: Vec::from_hex(KIND_SELL).unwrap().try_into().unwrap(),
             partially_fillable: false,
@@ -137,18 +450,22 @@ impl EthereumClient {
             .call()
             .await?;
 
-        let mock_signature = encoder::get_eip_1271_signature(SignatureData {
-            from_token: swap_request.from_token,
-            to_token: swap_request.to_token,
-            receiver: swap_request.receiver,
-            sell_amount_after_fees: swap_request.amount_in,
-            buy_amount_after_fees_and_slippage: U256::MAX,
-            valid_to: u32::MAX as u64,
-            fee_amount: U256::zero(),
-            order_creator: swap_request.order_creator,
-            price_checker: swap_request.price_checker,
-            price_checker_data: &swap_request.price_checker_data,
-        });
+        let mock_signature = swap_request
+            .milkman
+            .adapter()
+            .eip_1271_signature(SignatureData {
+                from_token: swap_request.from_token,
+                to_token: swap_request.to_token,
+                receiver: swap_request.receiver,
+                sell_amount_after_fees: swap_request.amount_in,
+                buy_amount_after_fees_and_slippage: U256::MAX,
+                valid_to: u32::MAX as u64,
+                app_data,
+                fee_amount: U256::zero(),
+                order_creator: swap_request.order_creator,
+                price_checker: swap_request.price_checker,
+                price_checker_data: &swap_request.price_checker_data,
+            });
 
         debug!(
             "isValidSignature({:?},{:?})",
@@ -170,50 +487,107 @@ impl EthereumClient {
     }
 }
 
-impl From<&SwapRequestedFilter> for Swap {
-    fn from(raw_swap_request: &SwapRequestedFilter) -> Self {
-        Self {
-            order_contract: raw_swap_request.order_contract,
-            order_creator: raw_swap_request.order_creator,
-            receiver: raw_swap_request.to,
-            from_token: raw_swap_request.from_token,
-            to_token: raw_swap_request.to_token,
-            amount_in: raw_swap_request.amount_in,
-            price_checker: raw_swap_request.price_checker,
-            price_checker_data: raw_swap_request.price_checker_data.clone(),
-        }
+/// Like `is_revert`, for a contract call's error that was passed on.
+pub fn is_contract_revert(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<ContractError<Provider<Http>>>(), Some(err) if is_revert(err))
+}
+
+/// Whether a call failed because the contract reverted, rather than because
+/// the node couldn't run it. Nodes answer `eth_call`s that revert with a
+/// JSON-RPC error, code 3 if the revert has data, and otherwise a message
+/// starting with "execution reverted", which may be followed by the reason.
+fn is_revert(err: &ContractError<Provider<Http>>) -> bool {
+    let provider_error = match err {
+        ContractError::MiddlewareError(err) | ContractError::ProviderError(err) => err,
+        _ => return false,
+    };
+    match provider_error {
+        ProviderError::JsonRpcClientError(err) => matches!(
+            err.downcast_ref::<HttpClientError>(),
+            Some(HttpClientError::JsonRpcError(err))
+                if err.code == 3 || err.message.starts_with("execution reverted")
+        ),
+        _ => false,
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::network::Network;
+
+    #[test]
+    fn test_is_revert() {
+        fn json_rpc_error(error: serde_json::Value) -> ContractError<Provider<Http>> {
+            let err = HttpClientError::JsonRpcError(serde_json::from_value(error).unwrap());
+            ContractError::MiddlewareError(ProviderError::JsonRpcClientError(Box::new(err)))
+        }
+
+        assert!(is_revert(&json_rpc_error(serde_json::json!({
+            "code": 3,
+            "message": "execution reverted: price too low",
+            "data": "0x08c379a0",
+        }))));
+        assert!(is_revert(&json_rpc_error(serde_json::json!({
+            "code": -32000,
+            "message": "execution reverted",
+        }))));
+        assert!(is_revert(&json_rpc_error(serde_json::json!({
+            "code": -32000,
+            "message": "execution reverted: price too low",
+        }))));
+        assert!(!is_revert(&json_rpc_error(serde_json::json!({
+            "code": -32000,
+            "message": "header not found",
+        }))));
+        assert!(is_contract_revert(&anyhow::Error::from(json_rpc_error(
+            serde_json::json!({ "code": 3, "message": "execution reverted" })
+        ))));
+        assert!(!is_revert(&ContractError::ProviderError(
+            ProviderError::CustomError("the revert was not what failed".to_string())
+        )));
+    }
+
+    /// Set by CI, which can reach mainnet, unlike some build environments.
+    const LIVE_TESTS_VARIABLE: &str = "MILKMAN_LIVE_TESTS";
 
     #[tokio::test]
     async fn test_ethereum_client() {
+        if std::env::var_os(LIVE_TESTS_VARIABLE).is_none() {
+            eprintln!(
+                "skipping test_ethereum_client, it needs a live mainnet node and {} to be set",
+                LIVE_TESTS_VARIABLE
+            );
+            return;
+        }
+        let network = Network::Mainnet;
         let config = Configuration {
             infura_api_key: Some("e74132f416d346308763252779d7df22".to_string()),
-            network: "goerli".to_string(),
-            milkman_address: "0x5D9C7CBeF995ef16416D963EaCEEC8FcA2590731"
-                .parse()
-                .unwrap(),
-            hash_helper_address: "0x429A101f42781C53c088392956c95F0A32437b8C"
-                .parse()
-                .unwrap(),
-            starting_block_number: None,
+            milkman_deployments: vec![network.default_milkman_address().unwrap().into()],
+            hash_helper_address: network.default_hash_helper_address().unwrap(),
             polling_frequency_secs: 15,
             node_base_url: None,
-            slippage_tolerance_bps: 50,
+            cow_api_base_url: network.cow_api_base_url().unwrap().to_string(),
+            max_backoff_secs: 300,
+            network,
+            ..Configuration::for_tests()
         };
 
         let eth_client = EthereumClient::new(&config).expect("Unable to create Ethereum client");
 
+        let chain_id = eth_client
+            .get_chain_id()
+            .await
+            .expect("Unable to get chain ID");
+
+        assert_eq!(chain_id, config.network.chain_id());
+
         let latest_block_num = eth_client
             .get_latest_block_number()
             .await
             .expect("Unable to get latest block number");
 
-        assert!(latest_block_num > 7994445);
+        assert!(latest_block_num > 16124151);
 
         let chain_timestamp = eth_client
             .get_chain_timestamp()
@@ -222,58 +596,26 @@ mod tests {
 
         assert!(chain_timestamp > 1669053987);
 
-        let goerli_uni_addr = "0x1f9840a85d5aF5bf1D1762F925BDADdC4201F984"
+        let uni_addr = "0x1f9840a85d5aF5bf1D1762F925BDADdC4201F984"
             .parse()
             .unwrap();
-        let goerli_uni_whale = "0x41653c7d61609D856f29355E404F310Ec4142Cfb"
+        let uni_whale = "0x1a9C8182C09F50C8318d769245beA52c32BE35BC" // Uniswap timelock
             .parse()
             .unwrap();
 
         let balance = eth_client
-            .get_balance_of(goerli_uni_addr, goerli_uni_whale)
+            .get_balance_of(uni_addr, uni_whale)
             .await
             .expect("Unable to get balance");
 
         assert!(balance > 0.into());
 
+        // same starting block as integration-test.sh
         let requested_swaps = eth_client
-            .get_requested_swaps(0, latest_block_num)
+            .get_requested_swaps(16124151, latest_block_num)
             .await
             .expect("Unable to get requested swaps");
 
         assert!(!requested_swaps.is_empty());
     }
-
-    #[test]
-    fn test_convert_swap() {
-        let order_contract = Address::random();
-        let order_creator = Address::random();
-        let amount_in: U256 = rand::thread_rng().gen::<u128>().into();
-        let from_token = Address::random();
-        let to_token = Address::random();
-        let to = Address::random();
-        let price_checker = Address::random();
-        let price_checker_data: Bytes = rand::thread_rng().gen::<[u8; 1000]>().into();
-
-        let raw_swap = SwapRequestedFilter {
-            order_contract,
-            order_creator,
-            amount_in,
-            from_token,
-            to_token,
-            to,
-            price_checker,
-            price_checker_data: price_checker_data.clone(),
-        };
-        let converted: Swap = (&raw_swap).into();
-
-        assert_eq!(converted.order_contract, order_contract);
-        assert_eq!(converted.order_creator, order_creator);
-        assert_eq!(converted.amount_in, amount_in);
-        assert_eq!(converted.from_token, from_token);
-        assert_eq!(converted.to_token, to_token);
-        assert_eq!(converted.receiver, to);
-        assert_eq!(converted.price_checker, price_checker);
-        assert_eq!(converted.price_checker_data, price_checker_data);
-    }
 }
@@ -1,17 +1,45 @@
 use anyhow::{bail, Context, Result};
+use ethers::types::U256;
+use futures::{
+    future,
+    stream::{self, StreamExt},
+};
 use hex::ToHex;
-use std::{collections::HashMap, time::Duration};
-use tokio::time::sleep;
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{watch, Mutex},
+    time::{sleep, Instant},
+};
 use tracing::Instrument;
 
+mod circuit_breaker;
+use crate::circuit_breaker::CircuitBreaker;
+
+mod cli;
+use crate::cli::Command;
+
+mod commands;
+
 mod configuration;
-use crate::configuration::Configuration;
+use crate::configuration::{BotConfiguration, Configuration};
 
 mod ethereum_client;
 use crate::ethereum_client::EthereumClient;
 
 mod cow_api_client;
-use crate::cow_api_client::{CowAPIClient, Order};
+use crate::cow_api_client::{CowAPIClient, CowApiError, OrderCreation, OrderStatus, Quote};
+
+mod app_data;
+use crate::app_data::{AppData, OrderClass};
 
 mod encoder;
 use crate::encoder::SignatureData;
@@ -21,25 +49,264 @@ use crate::types::Swap;
 
 mod constants;
 
+mod metrics;
+use crate::metrics::NetworkMetrics;
+
+mod milkman_adapter;
+
+mod network;
+
+mod reload;
+
+mod self_check;
+
+mod slippage;
+
+mod buy_amount;
+
+mod shutdown;
+use crate::shutdown::Shutdown;
+
+mod state;
+use crate::state::NetworkState;
+
+mod swap_record;
+use crate::swap_record::{Retry, SwapRecord};
+
+/// How many blocks before the checkpoint each scan for swap requests starts.
+const LOOKBACK_BLOCKS: u64 = 100;
+
+/// How long a network's startup checks get, as the node's client has no
+/// timeout of its own.
+const SELF_CHECK_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt::init();
+
+    let command = Command::parse(std::env::args().skip(1)).expect("Unable to parse arguments.");
+
+    match command {
+        Command::Run => {
+            let config = BotConfiguration::get_from_environment()
+                .expect("Unable to get configuration from the environment variables."); // .expect() because every decision to panic should be conscious, not just triggered by a `?` that we didn't think about
+            run(config).await
+        }
+        Command::CheckConfig => {
+            let config = BotConfiguration::get_from_environment()
+                .expect("Unable to get configuration from the environment variables.");
+            let mut passed = true;
+            for network_config in &config.networks {
+                let report = self_check::check_configuration(network_config).await;
+                println!("=== {} ===\n{}", network_config.network, report);
+                passed &= report.passed();
+            }
+            if !passed {
+                std::process::exit(1);
+            }
+        }
+        Command::RequestSwap(args) => commands::request_swap(&single_network_config(), args)
+            .await
+            .expect("Unable to request swap."),
+        Command::CancelSwap(args) => commands::cancel_swap(&single_network_config(), args)
+            .await
+            .expect("Unable to cancel swap."),
+    }
+}
+
+fn single_network_config() -> Configuration {
+    Configuration::get_from_environment()
+        .expect("Unable to get configuration from the environment variables.")
+}
+
+async fn run(config: BotConfiguration) {
+    tracing::info!("=== MILKMAN BOT STARTING ===");
+
+    let shutdown = Shutdown::listen();
+
+    // Only a configuration that doesn't match the chain stops the bot. A node
+    // or API that's down is retried by the network's loop, which starts out
+    // degraded, without affecting the other networks. The networks are checked
+    // concurrently, so that one whose node doesn't answer doesn't hold up the
+    // others.
+    let reports = future::join_all(config.networks.iter().map(|network_config| {
+        tokio::time::timeout(
+            SELF_CHECK_TIMEOUT,
+            self_check::check_configuration(network_config),
+        )
+    }))
+    .await;
+    for (network_config, report) in config.networks.iter().zip(reports) {
+        let report = match report {
+            Ok(report) => report,
+            Err(_) => {
+                tracing::warn!(
+                    "Configuration check for {} didn't finish within {:?}, starting it degraded.",
+                    network_config.network,
+                    SELF_CHECK_TIMEOUT
+                );
+                NetworkMetrics::new(&network_config.network).set_degraded(true);
+                continue;
+            }
+        };
+        if report.has_mismatches() {
+            panic!(
+                "Configuration check for {} failed:\n{}",
+                network_config.network, report
+            );
+        }
+        if report.passed() {
+            tracing::info!(
+                "Configuration check for {}:\n{}",
+                network_config.network,
+                report
+            );
+        } else {
+            tracing::warn!(
+                "Configuration check for {} couldn't reach everything, starting it degraded:\n{}",
+                network_config.network,
+                report
+            );
+            NetworkMetrics::new(&network_config.network).set_degraded(true);
+        }
+    }
+
+    if let Some(state_dir) = &config.state_dir {
+        fs::create_dir_all(state_dir).expect("Unable to create the state directory.");
+    }
+
+    if let Some(metrics_port) = config.metrics_port {
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(metrics_port).await {
+                tracing::error!("metrics server stopped – {:?}", err);
+            }
+        });
+    }
+
+    let mut senders = HashMap::new();
+    let mut supervisors = Vec::new();
+    for network_config in &config.networks {
+        let state_file = config
+            .state_dir
+            .as_deref()
+            .map(|state_dir| state::state_file(state_dir, &network_config.network));
+        let state = match &state_file {
+            Some(state_file) => {
+                NetworkState::load(state_file).expect("Unable to load the saved network state.")
+            }
+            None => NetworkState::default(),
+        };
+        let (sender, receiver) = watch::channel(network_config.clone());
+        senders.insert(network_config.network.to_string(), sender);
+        supervisors.push(tokio::spawn(supervise_network(
+            receiver,
+            NetworkRuntime {
+                state: Arc::new(Mutex::new(state)),
+                state_file,
+                shutdown: shutdown.clone(),
+                shutdown_timeout: Duration::from_secs(config.shutdown_timeout_secs),
+                exit_on_give_up: config.exit_on_give_up,
+            },
+        )));
+    }
+    tokio::spawn(reload::watch_configuration(config, senders));
+
+    let mut gave_up = false;
+    for supervisor in supervisors {
+        match supervisor.await {
+            Ok(exit) => gave_up |= exit == NetworkExit::GaveUp,
+            Err(err) => tracing::error!("network supervisor stopped – {:?}", err),
+        }
+    }
+
+    if gave_up {
+        tracing::error!("=== MILKMAN BOT GAVE UP ===");
+        std::process::exit(1);
+    }
+    tracing::info!("=== MILKMAN BOT STOPPED ===");
+}
+
+/// What a network's loop needs besides its configuration, shared by every
+/// restart of the loop.
+#[derive(Debug, Clone)]
+struct NetworkRuntime {
+    state: Arc<Mutex<NetworkState>>,
+    state_file: Option<PathBuf>, // where the state is saved, if anywhere
+    shutdown: Shutdown,
+    shutdown_timeout: Duration, // how long in-flight swaps get to finish on shutdown
+    exit_on_give_up: bool,      // whether giving up stops the other networks too
+}
+
+/// Why a network's loop stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NetworkExit {
+    Shutdown,
+    GaveUp, // the node was down for longer than `give_up_after_secs`
+}
+
+/// Run a network's loop in its own task, restarting it whenever it panics.
+/// Each network has its own task, so a panic or an RPC outage on one chain
+/// doesn't affect the others. A restarted loop runs with the latest reloaded
+/// configuration.
+async fn supervise_network(
+    config: watch::Receiver<Configuration>,
+    mut runtime: NetworkRuntime,
+) -> NetworkExit {
+    let network = config.borrow().network.clone();
+    let span = tracing::info_span!("network", network = %network);
+    let metrics = NetworkMetrics::new(&network);
+
+    loop {
+        let network_loop = run_network(config.clone(), runtime.clone()).instrument(span.clone());
+        match tokio::spawn(network_loop).await {
+            Ok(exit) => return exit,
+            Err(err) => {
+                tracing::error!(parent: &span, "network loop stopped, restarting – {:?}", err);
+                metrics.network_restarted();
+                let polling_frequency_secs = config.borrow().polling_frequency_secs;
+                tokio::select! {
+                    _ = sleep(Duration::from_secs(polling_frequency_secs)) => (),
+                    _ = runtime.shutdown.requested() => return NetworkExit::Shutdown,
+                }
+            }
+        }
+    }
+}
+
 /// Every x seconds, do the following:
 /// - check for new Milkman swap requests, and enqueue them into a swap queue
-/// - check if items in the swap queue have already been fulfilled
+/// - check if the swaps in the queue that are due (see `SwapRecord`) have
+///   already been fulfilled
 ///     - if the swap has already been fulfilled, dequeue it
 ///     - if the swap hasn't been fulfilled, create an order via the CoW API
 ///
 /// This implies that multiple API orders can be created for a single swap request.
 /// We accept this trade-off because it gives us additional simplicity, and the
 /// marginal cloud compute cost to CoW is likely to be very small.
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt::init();
-    tracing::info!("=== MILKMAN BOT STARTING ===");
-
-    let config = Configuration::get_from_environment()
-        .expect("Unable to get configuration from the environment variables."); // .expect() because every decision to panic should be conscious, not just triggered by a `?` that we didn't think about
+///
+/// Once a shutdown is requested, discovery is abandoned, no new swap is
+/// handled, the swap being handled gets until the shutdown timeout to finish,
+/// and the state is saved.
+async fn run_network(
+    mut config_receiver: watch::Receiver<Configuration>,
+    runtime: NetworkRuntime,
+) -> NetworkExit {
+    let NetworkRuntime {
+        state,
+        state_file,
+        mut shutdown,
+        shutdown_timeout,
+        exit_on_give_up,
+    } = runtime;
+    let mut config = config_receiver.borrow_and_update().clone();
+    let mut state = state.lock().await;
+    // a loop that panicked may have left swaps being quoted
+    state.skip_quoting();
 
-    let eth_client = EthereumClient::new(&config).expect("Unable to create the Ethereum client.");
-    let cow_api_client = CowAPIClient::new(&config);
+    let mut eth_client =
+        EthereumClient::new(&config).expect("Unable to create the Ethereum client.");
+    let mut cow_api_client = CowAPIClient::new(&config);
+    let metrics = NetworkMetrics::new(&config.network);
 
     // During development, I found Infura's WebSockets endpoint to sometimes miss
     // swaps, so we pull in requested swaps by quering through a series of ranges.
@@ -48,102 +315,648 @@ async fn main() {
     // After that first request, `range_start` would be set to 20. By the time
     // of the second request, we will query the current block number, let's say
     // 22, and so query from 20 to 22. This is repeated in an infinite loop.
-    let mut range_start = config.starting_block_number.unwrap_or(
-        eth_client
-            .get_latest_block_number()
-            .await
-            .expect("Unable to get latest block number before starting."),
-    );
+    //
+    // Without a checkpoint or a starting block number, the range starts at the
+    // first block number we manage to get.
+    let mut range_start = state.range_start.or(config.starting_block_number);
+    tracing::debug!("range start: {:?}", range_start);
 
-    tracing::debug!("range start: {}", range_start);
+    // A failing node slows the loop down rather than crashing it, and after
+    // enough failures in a row, swaps aren't handled until it recovers.
+    let mut breaker = CircuitBreaker::default();
+    // a network whose startup checks couldn't reach everything starts
+    // degraded, until it gets through discovery once
+    let mut discovered = false;
 
-    let mut swap_queue = HashMap::new();
+    let exit = 'iterations: loop {
+        tokio::select! {
+            _ = sleep(breaker.delay(&config)) => (),
+            _ = shutdown.requested() => break NetworkExit::Shutdown,
+        }
 
-    loop {
-        sleep(Duration::from_secs(config.polling_frequency_secs)).await;
+        if config_receiver.has_changed().unwrap_or(false) {
+            let reloaded = config_receiver.borrow_and_update().clone();
+            if reloaded.node_base_url != config.node_base_url
+                || reloaded.infura_api_key != config.infura_api_key
+            {
+                match EthereumClient::new(&reloaded) {
+                    Ok(reloaded_client) => eth_client = reloaded_client,
+                    Err(err) => {
+                        tracing::error!(
+                            "unable to reconnect to the node, keeping the current connection – {:?}",
+                            err
+                        );
+                    }
+                }
+            }
+            cow_api_client = CowAPIClient::new(&reloaded);
+            config = reloaded;
+        }
 
-        let range_end = eth_client
-            .get_latest_block_number()
+        // Discovery only adds to the queue, and a restarted bot scans the same
+        // blocks again, so a shutdown abandons it rather than waiting on a node
+        // that may not answer.
+        let latest_block_number = match shutdown
+            .unless_requested(eth_client.get_latest_block_number())
             .await
-            .expect("Unable to get latest block number."); // should we panic here if we can't get it? another option would be continuing in the loop, but then we might not observe that the bot is really `down`
+        {
+            Some(latest_block_number) => latest_block_number,
+            None => break NetworkExit::Shutdown,
+        };
+        let range_end = match latest_block_number {
+            Ok(range_end) => range_end,
+            Err(err) => {
+                tracing::warn!("unable to get latest block number – {:?}", err);
+                if record_node_failure(&mut breaker, &config, &metrics) {
+                    break NetworkExit::GaveUp;
+                }
+                continue;
+            }
+        };
+        let from_block = *range_start.get_or_insert(range_end);
 
         tracing::debug!("range end: {}", range_end);
 
-        // add the - 100 to cast a wider net since Infura sometimes doesn't reply
-        let requested_swaps = match eth_client
-            .get_requested_swaps(range_start - 100, range_end)
+        // scan `LOOKBACK_BLOCKS` before the checkpoint to cast a wider net,
+        // since Infura sometimes doesn't reply
+        let scan_start = from_block.saturating_sub(LOOKBACK_BLOCKS);
+        state.forget_finished_before(scan_start);
+        let requested_swaps = match shutdown
+            .unless_requested(eth_client.get_requested_swaps(scan_start, range_end))
             .await
         {
+            Some(requested_swaps) => requested_swaps,
+            None => break NetworkExit::Shutdown,
+        };
+        let requested_swaps = match requested_swaps {
             Ok(swaps) => swaps,
             Err(err) => {
                 tracing::error!("unable to get requested swaps – {:?}", err);
+                if record_node_failure(&mut breaker, &config, &metrics) {
+                    break NetworkExit::GaveUp;
+                }
                 continue;
             }
         };
+        if breaker.record_success(&config) {
+            tracing::info!("Node recovered, resuming swap handling.");
+            metrics.set_degraded(false);
+        } else if !discovered {
+            metrics.set_degraded(false);
+        }
+        discovered = true;
 
         if !requested_swaps.is_empty() {
             tracing::info!(
                 "Found {} requested swaps between blocks {} and {}",
                 requested_swaps.len(),
-                range_start,
+                from_block,
                 range_end
             );
         }
 
         for requested_swap in requested_swaps {
+            // swaps that finished are still in the scanned blocks for a while
+            if state
+                .finished_swaps
+                .contains_key(&requested_swap.order_contract)
+            {
+                continue;
+            }
+            if let Some(record) = state.swap_queue.get_mut(&requested_swap.order_contract) {
+                // the ranges overlap, so a swap whose log moved was reorged
+                if requested_swap.provenance.is_some()
+                    && record.swap.provenance != requested_swap.provenance
+                {
+                    tracing::info!(
+                        "Swap with order contract ({}) was reorged from {:?} to {:?}.",
+                        requested_swap.order_contract,
+                        record.swap.provenance,
+                        requested_swap.provenance
+                    );
+                    record.swap.provenance = requested_swap.provenance;
+                }
+                continue;
+            }
+
+            let verified = match shutdown
+                .unless_requested(eth_client.verify_swap_hash(&requested_swap))
+                .await
+            {
+                Some(verified) => verified,
+                // before the checkpoint moves past the swaps not queued yet
+                None => break 'iterations NetworkExit::Shutdown,
+            };
+            match verified {
+                Ok(true) => (),
+                Ok(false) => {
+                    tracing::error!(
+                        "Swap with order contract ({}) doesn't match its swap hash, is Milkman {:#x} really {}? Skipping it.",
+                        requested_swap.order_contract,
+                        requested_swap.milkman.address,
+                        requested_swap.milkman.version
+                    );
+                    continue;
+                }
+                Err(err) => tracing::warn!("unable to verify swap hash – {:?}", err),
+            }
+
             tracing::info!("Inserting following swap in queue: {:?}", requested_swap);
+            if let (Some(block_explorer_url), Some(provenance)) = (
+                config.network.block_explorer_url(),
+                requested_swap.provenance,
+            ) {
+                tracing::info!(
+                    "Swap requested in {}/tx/{:#x}",
+                    block_explorer_url,
+                    provenance.transaction_hash
+                );
+            }
             tracing::debug!(
                 "Price checker data hex: 0x{}",
                 requested_swap.price_checker_data.encode_hex::<String>()
             );
-            swap_queue.insert(requested_swap.order_contract, requested_swap);
+            state.swap_queue.insert(
+                requested_swap.order_contract,
+                SwapRecord::new(requested_swap, swap_record::now()),
+            );
+            metrics.swap_discovered();
         }
+        metrics.set_swaps_in_queue(state.swap_queue.len());
+        metrics.set_swaps_by_state(state.swap_queue.values());
 
-        for requested_swap in swap_queue.clone().values() {
-            let is_swap_fulfilled = match is_swap_fulfilled(requested_swap, &eth_client).await {
-                Ok(res) => res,
-                Err(err) => {
-                    tracing::error!("unable to determine if swap was fulfilled – {:?}", err);
-                    continue;
+        // the queue now holds every swap up to `range_end`, so the checkpoint
+        // can move even if we stop before handling them
+        range_start = Some(range_end);
+        state.range_start = range_start;
+
+        if shutdown
+            .unless_requested(check_parked_balances(
+                &mut state,
+                &eth_client,
+                &config,
+                &metrics,
+            ))
+            .await
+            .is_none()
+        {
+            break NetworkExit::Shutdown;
+        }
+
+        // Due swaps are processed concurrently, up to `max_concurrent_swaps` at
+        // a time. A swap that hasn't started when a shutdown is requested, or
+        // when the node starts failing, is skipped until the next iteration.
+        let paused = AtomicBool::new(breaker.is_open(&config));
+        let now = swap_record::now();
+        let mut due_swaps = Vec::new();
+        for record in state.swap_queue.values_mut() {
+            if !record.is_due(now) {
+                continue;
+            }
+            match record.start_quoting(now) {
+                Ok(()) => due_swaps.push(record.clone()),
+                Err(err) => tracing::error!("{:?}", err),
+            }
+        }
+        // oldest first, so that which swaps go first doesn't depend on the queue's order
+        due_swaps.sort_by_key(|record| {
+            record
+                .swap
+                .provenance
+                .map(|provenance| provenance.position())
+        });
+        if !due_swaps.is_empty() {
+            tracing::debug!(
+                "{} of {} queued swaps are due",
+                due_swaps.len(),
+                state.swap_queue.len()
+            );
+        }
+        let mut outcomes = stream::iter(due_swaps)
+            .map(|record| {
+                let (eth_client, cow_api_client, config) = (&eth_client, &cow_api_client, &config);
+                let (shutdown, paused, metrics) = (&shutdown, &paused, &metrics);
+                let contract = format!("{:#x}", record.swap.order_contract);
+                async move {
+                    let (balance, outcome) =
+                        if shutdown.is_requested() || paused.load(Ordering::Relaxed) {
+                            (None, SwapOutcome::Skipped)
+                        } else {
+                            process_swap(&record, eth_client, cow_api_client, config, metrics).await
+                        };
+                    (record.swap.order_contract, balance, outcome)
+                }
+                .instrument(tracing::info_span!("handle_swap", contract))
+            })
+            .buffer_unordered(config.max_concurrent_swaps);
+
+        let mut shutdown_requested = shutdown.clone();
+        let mut shutdown_deadline = None;
+        let mut gave_up = false;
+        loop {
+            let next = match shutdown_deadline {
+                None => tokio::select! {
+                    next = outcomes.next() => next,
+                    _ = shutdown_requested.requested() => {
+                        tracing::info!("Shutting down, giving the swaps being handled {:?} to finish.", shutdown_timeout);
+                        shutdown_deadline = Some(Instant::now() + shutdown_timeout);
+                        continue;
+                    }
+                },
+                Some(deadline) => match tokio::time::timeout_at(deadline, outcomes.next()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        tracing::warn!("Abandoned handling the remaining swaps to shut down.");
+                        break;
+                    }
+                },
+            };
+            let (order_contract, balance, outcome) = match next {
+                Some(next) => next,
+                None => break,
+            };
+            let record = match state.swap_queue.get_mut(&order_contract) {
+                Some(record) => record,
+                None => continue,
+            };
+
+            let now = swap_record::now();
+            let was_parked = record.parked_at.is_some();
+            let mut unparked = false;
+            if let Some(balance) = balance {
+                if record.balance_checked(balance, now) {
+                    unparked = true;
+                    tracing::info!(
+                        "Balance of parked swap with order contract ({}) changed, unparking it.",
+                        order_contract
+                    );
+                    metrics.swap_unparked();
+                }
+            }
+
+            let applied = match outcome {
+                SwapOutcome::Finished { filled } => record.finished(filled, now),
+                SwapOutcome::OrderCreated {
+                    order_uid,
+                    order_class,
+                    valid_to,
+                    slippage_bps,
+                } => {
+                    metrics.order_created();
+                    record.order_posted(
+                        order_uid,
+                        order_class,
+                        valid_to,
+                        slippage_bps,
+                        now,
+                        &config,
+                    )
+                }
+                SwapOutcome::FeeExceedsAmount {
+                    fee_amount,
+                    max_fee_bps,
+                } => {
+                    metrics.fee_exceeded_amount();
+                    record.fee_exceeds_amount(fee_amount, max_fee_bps, now, &config)
+                }
+                SwapOutcome::HandlingFailed { error, retry } => {
+                    metrics.swap_error();
+                    record.failed(error, retry, now, &config)
                 }
+                SwapOutcome::NodeFailed => {
+                    gave_up |= record_node_failure(&mut breaker, &config, &metrics);
+                    paused.store(breaker.is_open(&config) || gave_up, Ordering::Relaxed);
+                    Ok(())
+                }
+                SwapOutcome::Skipped => Ok(()),
             };
+            if let Err(err) = applied {
+                tracing::error!("{:?}", err);
+            }
 
-            if is_swap_fulfilled {
+            let is_parked = record.parked_at.is_some();
+            if (!was_parked || unparked) && is_parked {
+                tracing::warn!(
+                    "Parking swap with order contract ({}) after {} failures in a row, {}s after it was requested, rechecking every {}s. Last error: {}",
+                    order_contract,
+                    record.consecutive_failures,
+                    record.age(now),
+                    config.parked_recheck_secs,
+                    record.last_error.as_deref().unwrap_or_default()
+                );
+                metrics.swap_parked();
+            } else if was_parked && !unparked && !is_parked {
                 tracing::info!(
-                    "Swap with order contract ({}) was fulfilled, removing from queue.",
-                    requested_swap.order_contract
+                    "Parked swap with order contract ({}) is {} now, unparking it.",
+                    order_contract,
+                    record.state
                 );
-                swap_queue.remove(&requested_swap.order_contract);
-            } else {
-                let contract = format!("{:#x}", requested_swap.order_contract);
-                async {
-                    if let Err(err) =
-                        handle_swap(requested_swap, &eth_client, &cow_api_client, &config).await
-                    {
-                        tracing::error!("unable to handle swap {:?}", err);
-                    }
-                }
-                .instrument(tracing::info_span!("handle_swap", contract))
-                .await
+                metrics.swap_unparked();
+            }
+
+            let swap_state = record.state;
+            if swap_state.is_final() {
+                tracing::info!(
+                    "Swap with order contract ({}) was {}, removing from queue.",
+                    order_contract,
+                    swap_state
+                );
+                state.finish(order_contract, range_end);
+                metrics.swap_fulfilled();
             }
         }
+        drop(outcomes);
 
-        range_start = range_end;
+        // swaps that weren't handled after all go back to where they were
+        state.skip_quoting();
+        metrics.set_swaps_in_queue(state.swap_queue.len());
+        metrics.set_swaps_by_state(state.swap_queue.values());
+
+        if gave_up {
+            break NetworkExit::GaveUp;
+        }
+        if shutdown.is_requested() {
+            break NetworkExit::Shutdown;
+        }
+
+        if let Some(state_file) = &state_file {
+            if let Err(err) = state.save(state_file) {
+                tracing::error!("unable to save network state – {:?}", err);
+            }
+        }
+    };
+
+    // giving up only stops this network, unless the operator wants the whole
+    // bot to stop, so that one chain's outage doesn't affect the others
+    if exit == NetworkExit::GaveUp {
+        if exit_on_give_up {
+            tracing::error!(
+                "Node has been failing for over {}s, giving up and shutting down.",
+                config.give_up_after_secs.unwrap_or_default()
+            );
+            shutdown.request();
+        } else {
+            tracing::error!(
+                "Node has been failing for over {}s, giving up on this network.",
+                config.give_up_after_secs.unwrap_or_default()
+            );
+        }
     }
+
+    let checkpoint = match range_start {
+        Some(range_start) => format!("at block {}", range_start),
+        None => "unset".to_string(),
+    };
+    match &state_file {
+        Some(state_file) => match state.save(state_file) {
+            Ok(()) => tracing::info!(
+                "Stopped with {} queued swaps and the checkpoint {}, saved to `{}`.",
+                state.swap_queue.len(),
+                checkpoint,
+                state_file.display()
+            ),
+            Err(err) => tracing::error!("Stopped, but unable to save network state – {:?}", err),
+        },
+        None => tracing::info!(
+            "Stopped with {} queued swaps and the checkpoint {}, not saved as STATE_DIR isn't set.",
+            state.swap_queue.len(),
+            checkpoint
+        ),
+    }
+
+    exit
+}
+
+/// Check the balance of every parked swap that isn't due, which is one call
+/// each, so that a swap whose balance changed comes back straight away rather
+/// than on its next recheck.
+async fn check_parked_balances(
+    state: &mut NetworkState,
+    eth_client: &EthereumClient,
+    config: &Configuration,
+    metrics: &NetworkMetrics,
+) {
+    let now = swap_record::now();
+    let parked_swaps: Vec<Swap> = state
+        .swap_queue
+        .values()
+        .filter(|record| record.parked_at.is_some() && !record.is_due(now))
+        .map(|record| record.swap.clone())
+        .collect();
+    let balances: Vec<_> = stream::iter(parked_swaps)
+        .map(|swap| async move {
+            let balance = get_swap_balance(&swap, eth_client).await;
+            (swap.order_contract, balance)
+        })
+        .buffer_unordered(config.max_concurrent_swaps)
+        .collect()
+        .await;
+
+    for (order_contract, balance) in balances {
+        let balance = match balance {
+            Ok(balance) => balance,
+            Err(err) => {
+                tracing::warn!(
+                    "unable to check the balance of parked swap with order contract ({}) – {:?}",
+                    order_contract,
+                    err
+                );
+                continue;
+            }
+        };
+        let record = match state.swap_queue.get_mut(&order_contract) {
+            Some(record) => record,
+            None => continue,
+        };
+        if record.balance_checked(balance, now) {
+            tracing::info!(
+                "Balance of parked swap with order contract ({}) changed, unparking it.",
+                order_contract
+            );
+            metrics.swap_unparked();
+        }
+    }
+}
+
+/// Record a failed request to the node, returning whether to give up on it.
+fn record_node_failure(
+    breaker: &mut CircuitBreaker,
+    config: &Configuration,
+    metrics: &NetworkMetrics,
+) -> bool {
+    breaker.record_failure();
+    metrics.node_failed();
+    if breaker.consecutive_failures() == config.node_failure_threshold {
+        tracing::error!(
+            "Node failed {} times in a row, pausing swap handling until it recovers.",
+            breaker.consecutive_failures()
+        );
+        metrics.set_degraded(true);
+    }
+    tracing::info!("Retrying in {:?}.", breaker.delay(config));
+    breaker.should_give_up(config)
+}
+
+/// What happened to a queued swap in one iteration of a network's loop.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum SwapOutcome {
+    Finished {
+        filled: bool,
+    }, // filled, or cancelled if not
+    OrderCreated {
+        order_uid: Option<String>, // unknown if the orderbook already had the order
+        order_class: OrderClass,
+        valid_to: u64,
+        slippage_bps: Option<u16>, // none for orders whose buy amount doesn't come from a tolerance
+    },
+    FeeExceedsAmount {
+        fee_amount: U256, // deferred until gas is cheaper
+        max_fee_bps: u16,
+    },
+    HandlingFailed {
+        error: String,
+        retry: Retry,
+    },
+    NodeFailed, // unable to check whether the swap was fulfilled
+    Skipped,    // not started, because of a shutdown or a failing node
+}
+
+/// Dequeue the swap if it has been fulfilled, otherwise create an order for it.
+/// Also returns the swap's balance, if it could be checked.
+async fn process_swap(
+    record: &SwapRecord,
+    eth_client: &EthereumClient,
+    cow_api_client: &CowAPIClient,
+    config: &Configuration,
+    metrics: &NetworkMetrics,
+) -> (Option<U256>, SwapOutcome) {
+    let balance = match get_swap_balance(&record.swap, eth_client).await {
+        Ok(balance) => balance,
+        Err(err) => {
+            tracing::error!("unable to determine if swap was fulfilled – {:?}", err);
+            return (None, SwapOutcome::NodeFailed);
+        }
+    };
+
+    // if all `from` tokens are gone, the swap must have been completed or cancelled
+    if balance.is_zero() {
+        let filled = was_swap_filled(record, cow_api_client).await;
+        return (Some(balance), SwapOutcome::Finished { filled });
+    }
+
+    let outcome = match handle_swap(record, eth_client, cow_api_client, config, metrics).await {
+        Ok(outcome) => outcome,
+        Err(err) => {
+            tracing::error!("unable to handle swap {:?}", err);
+            SwapOutcome::HandlingFailed {
+                error: format!("{:#}", err),
+                retry: retry_policy(&err),
+            }
+        }
+    };
+    (Some(balance), outcome)
+}
+
+/// How to retry a swap that failed to be handled, going by the orderbook's
+/// error if there was one. Swaps that won't go through until something changes
+/// on-chain are parked, since a balance change unparks them.
+fn retry_policy(err: &anyhow::Error) -> Retry {
+    match err.downcast_ref::<CowApiError>() {
+        Some(
+            CowApiError::QuoteNotFound(_)
+            | CowApiError::QuoteExpired(_)
+            | CowApiError::InsufficientFee(_)
+            | CowApiError::DuplicateOrder(_)
+            | CowApiError::ServerError { .. },
+        ) => Retry::Soon,
+        Some(
+            CowApiError::UnsupportedToken(_)
+            | CowApiError::InsufficientBalance(_)
+            | CowApiError::InvalidEip1271Signature(_),
+        ) => Retry::Park,
+        Some(CowApiError::RateLimited) => Retry::Throttled,
+        Some(
+            CowApiError::NoLiquidity(_)
+            | CowApiError::SellAmountDoesNotCoverFee { .. }
+            | CowApiError::TooManyLimitOrders(_)
+            | CowApiError::Other { .. },
+        )
+        | None => Retry::BackOff,
+    }
+}
+
+/// For a swap that the orderbook wouldn't quote because its amount doesn't
+/// cover the fee, which is how dust swaps usually show up, the outcome of a fee
+/// that exceeds the amount. Without the fee in the error, it's taken to be
+/// the whole amount.
+fn uncovered_fee(err: &anyhow::Error, swap: &Swap, config: &Configuration) -> Option<SwapOutcome> {
+    match err.downcast_ref::<CowApiError>() {
+        Some(CowApiError::SellAmountDoesNotCoverFee { fee_amount, .. }) => {
+            Some(SwapOutcome::FeeExceedsAmount {
+                fee_amount: fee_amount.unwrap_or(swap.amount_in),
+                max_fee_bps: config.max_fee_bps_for(swap.from_token),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Whether a swap whose tokens are gone was filled rather than cancelled,
+/// judging by whether any of the orders we posted for it was.
+async fn was_swap_filled(record: &SwapRecord, cow_api_client: &CowAPIClient) -> bool {
+    for order_uid in record.order_uids.iter().rev() {
+        match cow_api_client.get_order_status(order_uid).await {
+            Ok(OrderStatus::Fulfilled) => return true,
+            Ok(_) => (),
+            Err(err) => {
+                // we did post an order, so it most likely was filled
+                tracing::warn!("unable to get status of order {} – {:?}", order_uid, err);
+                return true;
+            }
+        }
+    }
+    false
 }
 
 async fn handle_swap(
-    requested_swap: &Swap,
+    record: &SwapRecord,
     eth_client: &EthereumClient,
     cow_api_client: &CowAPIClient,
     config: &Configuration,
-) -> Result<()> {
+    metrics: &NetworkMetrics,
+) -> Result<SwapOutcome> {
+    let requested_swap = &record.swap;
     tracing::info!(
         "Handling swap with order contract ({})",
         requested_swap.order_contract
     );
+    let slippage = slippage::slippage_for(config, requested_swap);
+    let unfilled_orders = record.unfilled_orders(swap_record::now());
+    let escalated_bps = slippage::escalate(slippage.tolerance_bps, unfilled_orders, config);
+    if escalated_bps > slippage.tolerance_bps {
+        tracing::info!(
+            "using a slippage tolerance of {}, escalated to at most {} bps after {} unfilled orders",
+            slippage,
+            escalated_bps,
+            unfilled_orders
+        );
+    } else {
+        tracing::info!("using a slippage tolerance of {}", slippage);
+    }
+
+    // An order's appData holds its slippage, which is only known once its buy
+    // amount is, and that comes from the quote. So the gas estimate and the
+    // quote use the same document without the slippage, and the order and its
+    // signature the one with it. Both are uploaded, so that the orderbook
+    // knows every hash the bot sends it.
+    let quote_app_data = AppData::new(config, OrderClass::Market, None);
+    cow_api_client
+        .upload_app_data(&quote_app_data)
+        .await
+        .context("unable to upload appData")?;
+
     let mut verification_gas_limit = match eth_client
-        .get_estimated_order_contract_gas(config, requested_swap)
+        .get_estimated_order_contract_gas(config, requested_swap, quote_app_data.hash)
         .await
     {
         Ok(res) => res,
@@ -157,62 +970,377 @@ async fn handle_swap(
         verification_gas_limit
     );
 
-    let quote = match cow_api_client
-        .get_quote(
-            requested_swap.order_contract,
-            requested_swap.from_token,
-            requested_swap.to_token,
-            requested_swap.amount_in,
-            verification_gas_limit.as_u64(),
+    // orders rejected because their quote went stale are re-quoted and
+    // resubmitted straight away, up to `max_order_retries` times
+    let mut retries = 0;
+    loop {
+        let quote = match cow_api_client
+            .get_quote(
+                requested_swap.order_contract,
+                requested_swap.from_token,
+                requested_swap.to_token,
+                requested_swap.amount_in,
+                &quote_app_data,
+                verification_gas_limit.as_u64(),
+            )
+            .await
+        {
+            Ok(quote) => quote,
+            Err(err) => match uncovered_fee(&err, requested_swap, config) {
+                Some(outcome) => {
+                    tracing::warn!(
+                        "{}, deferring the swap for {}s",
+                        err,
+                        config.fee_recheck_secs
+                    );
+                    return Ok(outcome);
+                }
+                None => return Err(err.context("unable to fetch quote")),
+            },
+        };
+
+        // the market can't fill the swap yet, so leave an order for when it can
+        if config.limit_orders
+            && !eth_client
+                .check_price(
+                    requested_swap,
+                    quote.quote.fee_amount,
+                    quote.quote.buy_amount,
+                )
+                .await
+                .context("unable to check price")?
+        {
+            tracing::info!(
+                "price checker rejects the quoted buy amount of {}, posting a limit order",
+                quote.quote.buy_amount
+            );
+            return post_limit_order(requested_swap, eth_client, cow_api_client, config).await;
+        }
+
+        let fee_amount = quote.quote.fee_amount;
+        let max_fee_bps = config.max_fee_bps_for(requested_swap.from_token);
+        if swap_record::fee_exceeds_limit(fee_amount, requested_swap.amount_in, max_fee_bps) {
+            tracing::warn!(
+                "quoted fee of {} exceeds {} bps of the amount of {}, deferring the swap for {}s",
+                fee_amount,
+                max_fee_bps,
+                requested_swap.amount_in,
+                config.fee_recheck_secs
+            );
+            return Ok(SwapOutcome::FeeExceedsAmount {
+                fee_amount,
+                max_fee_bps,
+            });
+        }
+
+        let sell_amount_after_fees = requested_swap.amount_in - fee_amount;
+        let (buy_amount_after_fees_and_slippage, slippage_bps) = buy_amount(
+            requested_swap,
+            &quote,
+            slippage.tolerance_bps,
+            escalated_bps,
+            eth_client,
+            config,
         )
+        .await?;
+
+        // the order and its signature commit to the uploaded appData
+        let app_data = AppData::new(config, OrderClass::Market, slippage_bps);
+        cow_api_client
+            .upload_app_data(&app_data)
+            .await
+            .context("unable to upload appData")?;
+
+        let eip_1271_signature =
+            requested_swap
+                .milkman
+                .adapter()
+                .eip_1271_signature(SignatureData {
+                    from_token: requested_swap.from_token,
+                    to_token: requested_swap.to_token,
+                    receiver: requested_swap.receiver,
+                    sell_amount_after_fees,
+                    buy_amount_after_fees_and_slippage,
+                    valid_to: quote.quote.valid_to,
+                    app_data: app_data.hash,
+                    fee_amount,
+                    order_creator: requested_swap.order_creator,
+                    price_checker: requested_swap.price_checker,
+                    price_checker_data: &requested_swap.price_checker_data,
+                });
+        tracing::debug!(signature = ?eip_1271_signature.to_string());
+
+        let err = match cow_api_client
+            .create_order(&OrderCreation::eip_1271_sell(
+                requested_swap.order_contract,
+                &quote,
+                &app_data,
+                requested_swap.receiver,
+                sell_amount_after_fees,
+                buy_amount_after_fees_and_slippage,
+                eip_1271_signature,
+            ))
+            .await
+        {
+            Ok(order_uid) => {
+                return Ok(SwapOutcome::OrderCreated {
+                    order_uid: Some(order_uid),
+                    order_class: OrderClass::Market,
+                    valid_to: quote.quote.valid_to,
+                    slippage_bps,
+                })
+            }
+            Err(err) => err,
+        };
+
+        let api_error = err.downcast_ref::<CowApiError>();
+        if let Some(CowApiError::DuplicateOrder(_)) = api_error {
+            // we posted the same order before, so it's up already
+            tracing::info!("order was already posted – {}", err);
+            return Ok(SwapOutcome::OrderCreated {
+                order_uid: None,
+                order_class: OrderClass::Market,
+                valid_to: quote.quote.valid_to,
+                slippage_bps,
+            });
+        }
+        match api_error.and_then(CowApiError::requote_reason) {
+            Some(reason) if retries < config.max_order_retries => {
+                retries += 1;
+                tracing::warn!(
+                    "order rejected ({}), re-quoting and resubmitting ({}/{})",
+                    err,
+                    retries,
+                    config.max_order_retries
+                );
+                metrics.order_retried(reason);
+            }
+            _ => return Err(err.context("unable to create order via CoW API")),
+        }
+    }
+}
+
+/// Post a limit order at the price the swap's price checker expects, valid for
+/// `limit_order_validity_secs`, to rest in the orderbook until the market
+/// reaches it.
+async fn post_limit_order(
+    swap: &Swap,
+    eth_client: &EthereumClient,
+    cow_api_client: &CowAPIClient,
+    config: &Configuration,
+) -> Result<SwapOutcome> {
+    let expected_out = eth_client
+        .get_expected_out(swap, config.price_checker_kind(swap.price_checker))
         .await
+        .context("unable to get the price checker's expected output")?;
+    if !eth_client
+        .check_price(swap, U256::zero(), expected_out)
+        .await
+        .context("unable to check price")?
     {
-        Ok(res) => res,
-        Err(err) => {
-            bail!("unable to fetch quote - {:?}", err);
-        }
-    };
+        bail!(
+            "price checker rejects its own expected output of {}",
+            expected_out
+        );
+    }
+
+    let app_data = AppData::new(config, OrderClass::Limit, None);
+    cow_api_client
+        .upload_app_data(&app_data)
+        .await
+        .context("unable to upload appData")?;
 
-    let sell_amount_after_fees = requested_swap.amount_in - quote.fee_amount;
-    let buy_amount_after_fees_and_slippage =
-        quote.buy_amount_after_fee * (10000 - config.slippage_tolerance_bps) / 10000;
-
-    let eip_1271_signature = encoder::get_eip_1271_signature(SignatureData {
-        from_token: requested_swap.from_token,
-        to_token: requested_swap.to_token,
-        receiver: requested_swap.receiver,
-        sell_amount_after_fees,
-        buy_amount_after_fees_and_slippage,
-        valid_to: quote.valid_to,
-        fee_amount: quote.fee_amount,
-        order_creator: requested_swap.order_creator,
-        price_checker: requested_swap.price_checker,
-        price_checker_data: &requested_swap.price_checker_data,
+    let valid_to = swap_record::now() + config.limit_order_validity_secs;
+    let eip_1271_signature = swap.milkman.adapter().eip_1271_signature(SignatureData {
+        from_token: swap.from_token,
+        to_token: swap.to_token,
+        receiver: swap.receiver,
+        sell_amount_after_fees: swap.amount_in,
+        buy_amount_after_fees_and_slippage: expected_out,
+        valid_to,
+        app_data: app_data.hash,
+        fee_amount: U256::zero(),
+        order_creator: swap.order_creator,
+        price_checker: swap.price_checker,
+        price_checker_data: &swap.price_checker_data,
     });
     tracing::debug!(signature = ?eip_1271_signature.to_string());
 
-    cow_api_client
-        .create_order(Order {
-            order_contract: requested_swap.order_contract,
-            sell_token: requested_swap.from_token,
-            buy_token: requested_swap.to_token,
-            sell_amount: sell_amount_after_fees,
-            buy_amount: buy_amount_after_fees_and_slippage,
-            valid_to: quote.valid_to,
-            fee_amount: quote.fee_amount,
-            receiver: requested_swap.receiver,
-            eip_1271_signature: &eip_1271_signature,
-            quote_id: quote.id,
-        })
+    let order_uid = match cow_api_client
+        .create_order(&OrderCreation::eip_1271_limit_sell(
+            swap,
+            &app_data,
+            expected_out,
+            valid_to,
+            eip_1271_signature,
+        ))
         .await
-        .context("unable to create order via CoW API")?;
-    Ok(())
+    {
+        Ok(order_uid) => Some(order_uid),
+        Err(err) => match err.downcast_ref::<CowApiError>() {
+            Some(CowApiError::DuplicateOrder(_)) => None,
+            _ => return Err(err.context("unable to create limit order via CoW API")),
+        },
+    };
+    tracing::info!(
+        "posted a limit order to buy {} until {}",
+        expected_out,
+        valid_to
+    );
+    Ok(SwapOutcome::OrderCreated {
+        order_uid,
+        order_class: OrderClass::Limit,
+        valid_to,
+        slippage_bps: None,
+    })
 }
 
-async fn is_swap_fulfilled(swap: &Swap, eth_client: &EthereumClient) -> Result<bool> {
-    // if all `from` tokens are gone, the swap must have been completed or cancelled
-    Ok(eth_client
+/// The buy amount to post for a quote, following `buy_amount_strategy`, and
+/// the slippage tolerance it comes to, for strategies that have one.
+async fn buy_amount(
+    swap: &Swap,
+    quote: &Quote,
+    tolerance_bps: u16,
+    escalated_bps: u16,
+    eth_client: &EthereumClient,
+    config: &Configuration,
+) -> Result<(U256, Option<u16>)> {
+    let strategy = config.buy_amount_strategy;
+    if !strategy.uses_checker() {
+        let slippage_bps = accepted_slippage(
+            swap,
+            quote,
+            tolerance_bps,
+            escalated_bps,
+            eth_client,
+            config,
+        )
+        .await?;
+        return Ok((
+            with_slippage(quote.quote.buy_amount, slippage_bps),
+            Some(slippage_bps),
+        ));
+    }
+
+    let quoted = quote.quote.buy_amount;
+    let checker_minimum = buy_amount::search_checker_minimum(quoted, |min_out| {
+        eth_client.check_price(swap, quote.quote.fee_amount, min_out)
+    })
+    .await
+    .context("unable to find the smallest buy amount the price checker accepts")?;
+    let buy_amount = strategy.buy_amount(
+        quoted,
+        with_slippage(quoted, escalated_bps),
+        checker_minimum,
+    );
+    let slippage_bps = if buy_amount >= quoted {
+        0
+    } else {
+        ((quoted - buy_amount) * U256::from(10000) / quoted).as_u32() as u16
+    };
+    tracing::info!(
+        "price checker accepts buy amounts from {}, posting {} ({} bps under the quoted {}) with the `{}` strategy",
+        checker_minimum,
+        buy_amount,
+        slippage_bps,
+        quoted,
+        strategy
+    );
+    Ok((
+        buy_amount,
+        strategy.has_slippage_tolerance().then_some(slippage_bps),
+    ))
+}
+
+/// The most escalated slippage tolerance, stepping down from `escalated_bps`
+/// to `tolerance_bps`, whose buy amount the swap's price checker accepts. The
+/// unescalated tolerance is used whether or not the price checker accepts it.
+async fn accepted_slippage(
+    swap: &Swap,
+    quote: &Quote,
+    tolerance_bps: u16,
+    escalated_bps: u16,
+    eth_client: &EthereumClient,
+    config: &Configuration,
+) -> Result<u16> {
+    let mut slippage_bps = escalated_bps;
+    while slippage_bps > tolerance_bps {
+        let min_out = with_slippage(quote.quote.buy_amount, slippage_bps);
+        if eth_client
+            .check_price(swap, quote.quote.fee_amount, min_out)
+            .await
+            .context("unable to check price")?
+        {
+            tracing::info!(
+                "price checker accepts an escalated slippage tolerance of {} bps",
+                slippage_bps
+            );
+            return Ok(slippage_bps);
+        }
+        tracing::info!(
+            "price checker rejects a slippage tolerance of {} bps",
+            slippage_bps
+        );
+        slippage_bps = slippage_bps
+            .saturating_sub(config.slippage_step_bps)
+            .max(tolerance_bps);
+    }
+    Ok(tolerance_bps)
+}
+
+fn with_slippage(buy_amount: U256, slippage_bps: u16) -> U256 {
+    buy_amount * (10000 - slippage_bps) / 10000
+}
+
+async fn get_swap_balance(swap: &Swap, eth_client: &EthereumClient) -> Result<U256> {
+    eth_client
         .get_balance_of(swap.from_token, swap.order_contract)
-        .await?
-        .is_zero())
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::Address;
+
+    #[test]
+    fn test_uncovered_fee() {
+        let config = Configuration::for_tests();
+        let swap = Swap {
+            milkman: Address::random().into(),
+            order_contract: Address::random(),
+            order_creator: Address::random(),
+            receiver: Address::random(),
+            from_token: Address::random(),
+            to_token: Address::random(),
+            amount_in: U256::from(100),
+            price_checker: Address::random(),
+            price_checker_data: Default::default(),
+            provenance: None,
+        };
+        let uncovered = |fee_amount| {
+            anyhow::Error::from(CowApiError::SellAmountDoesNotCoverFee {
+                description: "fee too high".to_string(),
+                fee_amount,
+            })
+            .context("unable to fetch quote")
+        };
+
+        assert_eq!(
+            uncovered_fee(&uncovered(Some(U256::from(250))), &swap, &config),
+            Some(SwapOutcome::FeeExceedsAmount {
+                fee_amount: U256::from(250),
+                max_fee_bps: 10000,
+            })
+        );
+        assert_eq!(
+            uncovered_fee(&uncovered(None), &swap, &config),
+            Some(SwapOutcome::FeeExceedsAmount {
+                fee_amount: U256::from(100),
+                max_fee_bps: 10000,
+            })
+        );
+        let no_liquidity = anyhow::Error::from(CowApiError::NoLiquidity(String::new()));
+        assert_eq!(uncovered_fee(&no_liquidity, &swap, &config), None);
+    }
 }
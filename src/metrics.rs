@@ -0,0 +1,335 @@
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use prometheus::{Encoder, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use crate::network::Network;
+use crate::swap_record::{SwapRecord, SwapState};
+
+static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+/// Every metric carries a `network` label, so that one process running several
+/// networks can be told apart in dashboards.
+struct Metrics {
+    registry: Registry,
+    swaps_discovered: IntCounterVec,
+    swaps_in_queue: IntGaugeVec,
+    swaps_by_state: IntGaugeVec,
+    swaps_fulfilled: IntCounterVec,
+    orders_created: IntCounterVec,
+    swap_errors: IntCounterVec,
+    order_retries: IntCounterVec,
+    fees_exceeding_amount: IntCounterVec,
+    swaps_parked: IntCounterVec,
+    swaps_unparked: IntCounterVec,
+    network_restarts: IntCounterVec,
+    node_failures: IntCounterVec,
+    degraded: IntGaugeVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("milkman_bot".to_string()), None).unwrap();
+
+        fn register<T: prometheus::core::Collector + Clone + 'static>(
+            registry: &Registry,
+            collector: T,
+        ) -> T {
+            registry.register(Box::new(collector.clone())).unwrap();
+            collector
+        }
+
+        Self {
+            swaps_discovered: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("swaps_discovered", "Swaps found in `SwapRequested` events"),
+                    &["network"],
+                )
+                .unwrap(),
+            ),
+            swaps_in_queue: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new("swaps_in_queue", "Swaps waiting to be fulfilled"),
+                    &["network"],
+                )
+                .unwrap(),
+            ),
+            swaps_by_state: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new("swaps_by_state", "Queued swaps in each lifecycle state"),
+                    &["network", "state"],
+                )
+                .unwrap(),
+            ),
+            swaps_fulfilled: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "swaps_fulfilled",
+                        "Swaps removed from the queue, filled or cancelled",
+                    ),
+                    &["network"],
+                )
+                .unwrap(),
+            ),
+            orders_created: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("orders_created", "Orders posted to the CoW API"),
+                    &["network"],
+                )
+                .unwrap(),
+            ),
+            swap_errors: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("swap_errors", "Failed attempts at handling a swap"),
+                    &["network"],
+                )
+                .unwrap(),
+            ),
+            order_retries: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "order_retries",
+                        "Orders re-quoted and resubmitted after a recoverable rejection",
+                    ),
+                    &["network", "reason"],
+                )
+                .unwrap(),
+            ),
+            fees_exceeding_amount: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "fees_exceeding_amount",
+                        "Swaps deferred because their quoted fee exceeded `max_fee_bps` of their amount",
+                    ),
+                    &["network"],
+                )
+                .unwrap(),
+            ),
+            swaps_parked: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("swaps_parked", "Swaps parked after failing for too long"),
+                    &["network"],
+                )
+                .unwrap(),
+            ),
+            swaps_unparked: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "swaps_unparked",
+                        "Parked swaps whose balance changed or whose order went through",
+                    ),
+                    &["network"],
+                )
+                .unwrap(),
+            ),
+            network_restarts: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "network_restarts",
+                        "Times a network's loop panicked and was restarted",
+                    ),
+                    &["network"],
+                )
+                .unwrap(),
+            ),
+            node_failures: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("node_failures", "Failed requests to the network's node"),
+                    &["network"],
+                )
+                .unwrap(),
+            ),
+            degraded: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new(
+                        "degraded",
+                        "1 while swap handling is paused because the node keeps failing",
+                    ),
+                    &["network"],
+                )
+                .unwrap(),
+            ),
+            registry,
+        }
+    }
+}
+
+/// A view of the metrics for a single network.
+#[derive(Debug, Clone)]
+pub struct NetworkMetrics {
+    network: String,
+}
+
+impl NetworkMetrics {
+    pub fn new(network: &Network) -> Self {
+        Self {
+            network: network.to_string(),
+        }
+    }
+
+    pub fn swap_discovered(&self) {
+        METRICS
+            .swaps_discovered
+            .with_label_values(&[&self.network])
+            .inc();
+    }
+
+    pub fn set_swaps_in_queue(&self, swaps_in_queue: usize) {
+        METRICS
+            .swaps_in_queue
+            .with_label_values(&[&self.network])
+            .set(swaps_in_queue as i64);
+    }
+
+    pub fn set_swaps_by_state<'a>(&self, records: impl Iterator<Item = &'a SwapRecord>) {
+        let records: Vec<_> = records.collect();
+        for swap_state in SwapState::ALL {
+            let count = records
+                .iter()
+                .filter(|record| record.state == swap_state)
+                .count();
+            METRICS
+                .swaps_by_state
+                .with_label_values(&[&self.network, &swap_state.to_string()])
+                .set(count as i64);
+        }
+    }
+
+    pub fn swap_fulfilled(&self) {
+        METRICS
+            .swaps_fulfilled
+            .with_label_values(&[&self.network])
+            .inc();
+    }
+
+    pub fn order_created(&self) {
+        METRICS
+            .orders_created
+            .with_label_values(&[&self.network])
+            .inc();
+    }
+
+    pub fn swap_error(&self) {
+        METRICS
+            .swap_errors
+            .with_label_values(&[&self.network])
+            .inc();
+    }
+
+    pub fn order_retried(&self, reason: &str) {
+        METRICS
+            .order_retries
+            .with_label_values(&[&self.network, reason])
+            .inc();
+    }
+
+    pub fn fee_exceeded_amount(&self) {
+        METRICS
+            .fees_exceeding_amount
+            .with_label_values(&[&self.network])
+            .inc();
+    }
+
+    pub fn swap_parked(&self) {
+        METRICS
+            .swaps_parked
+            .with_label_values(&[&self.network])
+            .inc();
+    }
+
+    pub fn swap_unparked(&self) {
+        METRICS
+            .swaps_unparked
+            .with_label_values(&[&self.network])
+            .inc();
+    }
+
+    pub fn network_restarted(&self) {
+        METRICS
+            .network_restarts
+            .with_label_values(&[&self.network])
+            .inc();
+    }
+
+    pub fn node_failed(&self) {
+        METRICS
+            .node_failures
+            .with_label_values(&[&self.network])
+            .inc();
+    }
+
+    pub fn set_degraded(&self, degraded: bool) {
+        METRICS
+            .degraded
+            .with_label_values(&[&self.network])
+            .set(degraded as i64);
+    }
+}
+
+fn encode() -> Result<String> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&METRICS.registry.gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
+
+/// Serve the metrics in the Prometheus text format. This is deliberately
+/// minimal: every request gets the metrics, whatever its path.
+pub async fn serve(port: u16) -> Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    tracing::info!("Serving metrics on port {}", port);
+
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            let mut request = [0; 1024];
+            if let Err(err) = stream.read(&mut request).await {
+                tracing::debug!("unable to read metrics request – {:?}", err);
+                return;
+            }
+
+            let response = match encode() {
+                Ok(body) => format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                ),
+                Err(err) => {
+                    tracing::error!("unable to encode metrics – {:?}", err);
+                    "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+                }
+            };
+            if let Err(err) = stream.write_all(response.as_bytes()).await {
+                tracing::debug!("unable to write metrics response – {:?}", err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_carry_network_label() {
+        NetworkMetrics::new(&Network::Sepolia).order_created();
+        NetworkMetrics::new(&Network::Sepolia).set_swaps_in_queue(3);
+
+        let encoded = encode().unwrap();
+        assert!(encoded.contains("milkman_bot_orders_created{network=\"sepolia\"} 1"));
+        assert!(encoded.contains("milkman_bot_swaps_in_queue{network=\"sepolia\"} 3"));
+    }
+}
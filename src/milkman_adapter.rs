@@ -0,0 +1,209 @@
+use anyhow::{anyhow, Result};
+use ethers::abi::{self, RawLog, Token};
+use ethers::contract::{EthEvent, EthLogDecode};
+use ethers::types::{Address, Bytes, H256};
+use ethers::utils::keccak256;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::encoder::{self, SignatureData};
+use crate::ethereum_client::SwapRequestedFilter;
+use crate::types::Swap;
+
+/// Everything that depends on the layout of a Milkman release: how a swap is
+/// announced, how an order contract commits to its swap, and how the
+/// EIP-1271 signature is encoded. Supporting a new release means adding an
+/// adapter rather than forking the bot.
+pub trait MilkmanAdapter: Send + Sync {
+    /// topic0 of the event emitted when a swap is requested.
+    fn swap_requested_topic(&self) -> H256;
+
+    fn decode_swap_requested(&self, milkman: MilkmanDeployment, log: &RawLog) -> Result<Swap>;
+
+    /// The hash an order contract stores, and checks signatures against.
+    fn swap_hash(&self, swap: &Swap) -> H256;
+
+    fn eip_1271_signature(&self, signature_data: SignatureData<'_>) -> Bytes;
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MilkmanVersion {
+    /// The release in `abis/Milkman.json`, which deploys one order contract per
+    /// swap.
+    #[default]
+    V1,
+}
+
+impl MilkmanVersion {
+    pub fn adapter(self) -> &'static dyn MilkmanAdapter {
+        match self {
+            Self::V1 => &MilkmanV1,
+        }
+    }
+}
+
+impl FromStr for MilkmanVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(version: &str) -> Result<Self> {
+        match version {
+            "v1" => Ok(Self::V1),
+            _ => Err(anyhow!("unknown Milkman version `{}`", version)),
+        }
+    }
+}
+
+impl fmt::Display for MilkmanVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::V1 => write!(f, "v1"),
+        }
+    }
+}
+
+/// A Milkman contract the bot watches, and the release it runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MilkmanDeployment {
+    pub address: Address,
+    #[serde(default)]
+    pub version: MilkmanVersion,
+}
+
+impl MilkmanDeployment {
+    pub fn adapter(&self) -> &'static dyn MilkmanAdapter {
+        self.version.adapter()
+    }
+}
+
+impl From<Address> for MilkmanDeployment {
+    fn from(address: Address) -> Self {
+        Self {
+            address,
+            version: MilkmanVersion::default(),
+        }
+    }
+}
+
+/// Parses `<address>` or `<address>:<version>`.
+impl FromStr for MilkmanDeployment {
+    type Err = anyhow::Error;
+
+    fn from_str(deployment: &str) -> Result<Self> {
+        let (address, version) = match deployment.split_once(':') {
+            Some((address, version)) => (address, version.parse()?),
+            None => (deployment, MilkmanVersion::default()),
+        };
+
+        Ok(Self {
+            address: address.trim().parse()?,
+            version,
+        })
+    }
+}
+
+pub struct MilkmanV1;
+
+impl MilkmanAdapter for MilkmanV1 {
+    fn swap_requested_topic(&self) -> H256 {
+        SwapRequestedFilter::signature()
+    }
+
+    fn decode_swap_requested(&self, milkman: MilkmanDeployment, log: &RawLog) -> Result<Swap> {
+        let raw_swap_request = <SwapRequestedFilter as EthLogDecode>::decode_log(log)?;
+
+        Ok(Swap {
+            milkman,
+            order_contract: raw_swap_request.order_contract,
+            order_creator: raw_swap_request.order_creator,
+            receiver: raw_swap_request.to,
+            from_token: raw_swap_request.from_token,
+            to_token: raw_swap_request.to_token,
+            amount_in: raw_swap_request.amount_in,
+            price_checker: raw_swap_request.price_checker,
+            price_checker_data: raw_swap_request.price_checker_data,
+            provenance: None,
+        })
+    }
+
+    fn swap_hash(&self, swap: &Swap) -> H256 {
+        keccak256(abi::encode(&[
+            Token::Address(swap.order_creator),
+            Token::Address(swap.receiver),
+            Token::Address(swap.from_token),
+            Token::Address(swap.to_token),
+            Token::Uint(swap.amount_in),
+            Token::Address(swap.price_checker),
+            Token::Bytes(swap.price_checker_data.to_vec()),
+        ]))
+        .into()
+    }
+
+    fn eip_1271_signature(&self, signature_data: SignatureData<'_>) -> Bytes {
+        encoder::get_eip_1271_signature(signature_data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::U256;
+    use rand::prelude::*;
+
+    #[test]
+    fn test_decode_swap_requested() {
+        let milkman = MilkmanDeployment::from(Address::random());
+        let order_contract = Address::random();
+        let order_creator = Address::random();
+        let amount_in: U256 = rand::thread_rng().gen::<u128>().into();
+        let from_token = Address::random();
+        let to_token = Address::random();
+        let to = Address::random();
+        let price_checker = Address::random();
+        let price_checker_data: Bytes = rand::thread_rng().gen::<[u8; 1000]>().into();
+
+        let log = RawLog {
+            topics: vec![SwapRequestedFilter::signature()],
+            data: abi::encode(&[
+                Token::Address(order_contract),
+                Token::Address(order_creator),
+                Token::Uint(amount_in),
+                Token::Address(from_token),
+                Token::Address(to_token),
+                Token::Address(to),
+                Token::Address(price_checker),
+                Token::Bytes(price_checker_data.to_vec()),
+            ]),
+        };
+        let converted = MilkmanV1
+            .decode_swap_requested(milkman, &log)
+            .expect("failed to decode");
+
+        assert_eq!(converted.milkman, milkman);
+        assert_eq!(converted.order_contract, order_contract);
+        assert_eq!(converted.order_creator, order_creator);
+        assert_eq!(converted.amount_in, amount_in);
+        assert_eq!(converted.from_token, from_token);
+        assert_eq!(converted.to_token, to_token);
+        assert_eq!(converted.receiver, to);
+        assert_eq!(converted.price_checker, price_checker);
+        assert_eq!(converted.price_checker_data, price_checker_data);
+    }
+
+    #[test]
+    fn test_parse_deployment() {
+        let address = Address::random();
+
+        let deployment: MilkmanDeployment = format!("{:#x}", address).parse().unwrap();
+        assert_eq!(deployment, MilkmanDeployment::from(address));
+
+        let deployment: MilkmanDeployment = format!("{:#x}:v1", address).parse().unwrap();
+        assert_eq!(deployment.version, MilkmanVersion::V1);
+
+        assert!(format!("{:#x}:v0", address)
+            .parse::<MilkmanDeployment>()
+            .is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use anyhow::{bail, Result};
+use ethers::types::Address;
+use std::fmt;
+
+/// GPv2Settlement is deployed at the same address on every chain in the
+/// registry.
+const SETTLEMENT_CONTRACT_ADDRESS: &str = "0x9008D19f58AAbD9eD0D60971565AA8510560ab41";
+
+const MAINNET_MILKMAN_ADDRESS: &str = "0x11C76AD590ABDFFCD980afEC9ad951B160F02797";
+const MAINNET_HASH_HELPER_ADDRESS: &str = "0x49Fc95c908902Cf48f5F26ed5ADE284de3b55197";
+
+/// The chains the bot knows about, plus any other chain described entirely by
+/// the configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Network {
+    Mainnet,
+    GnosisChain,
+    Sepolia,
+    ArbitrumOne,
+    Custom { name: String, chain_id: u64 },
+}
+
+impl Network {
+    /// Resolve a network from its configured name. Names the registry doesn't
+    /// know need an explicit `chain_id`; for known names, `chain_id` is only
+    /// checked against the registry.
+    pub fn from_name(name: &str, chain_id: Option<u64>) -> Result<Self> {
+        let network = match name {
+            "mainnet" => Self::Mainnet,
+            "gnosis" | "xdai" => Self::GnosisChain,
+            "sepolia" => Self::Sepolia,
+            "arbitrum-one" | "arbitrum_one" => Self::ArbitrumOne,
+            _ => match chain_id {
+                Some(chain_id) => Self::Custom {
+                    name: name.to_string(),
+                    chain_id,
+                },
+                None => bail!(
+                    "unknown network `{}`; set `chain_id` to use it as a custom network",
+                    name
+                ),
+            },
+        };
+
+        if let Some(chain_id) = chain_id {
+            if chain_id != network.chain_id() {
+                bail!(
+                    "network `{}` has chain ID {}, not {}",
+                    name,
+                    network.chain_id(),
+                    chain_id
+                );
+            }
+        }
+
+        Ok(network)
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Mainnet => "mainnet",
+            Self::GnosisChain => "gnosis",
+            Self::Sepolia => "sepolia",
+            Self::ArbitrumOne => "arbitrum-one",
+            Self::Custom { name, .. } => name,
+        }
+    }
+
+    pub fn chain_id(&self) -> u64 {
+        match self {
+            Self::Mainnet => 1,
+            Self::GnosisChain => 100,
+            Self::Sepolia => 11155111,
+            Self::ArbitrumOne => 42161,
+            Self::Custom { chain_id, .. } => *chain_id,
+        }
+    }
+
+    /// The subdomain Infura serves this chain on, if it does.
+    pub fn infura_network(&self) -> Option<&'static str> {
+        match self {
+            Self::Mainnet => Some("mainnet"),
+            Self::Sepolia => Some("sepolia"),
+            Self::ArbitrumOne => Some("arbitrum-mainnet"),
+            Self::GnosisChain | Self::Custom { .. } => None,
+        }
+    }
+
+    pub fn cow_api_base_url(&self) -> Option<&'static str> {
+        match self {
+            Self::Mainnet => Some("https://api.cow.fi/mainnet"),
+            Self::GnosisChain => Some("https://api.cow.fi/xdai"),
+            Self::Sepolia => Some("https://api.cow.fi/sepolia"),
+            Self::ArbitrumOne => Some("https://api.cow.fi/arbitrum_one"),
+            Self::Custom { .. } => None,
+        }
+    }
+
+    pub fn block_explorer_url(&self) -> Option<&'static str> {
+        match self {
+            Self::Mainnet => Some("https://etherscan.io"),
+            Self::GnosisChain => Some("https://gnosisscan.io"),
+            Self::Sepolia => Some("https://sepolia.etherscan.io"),
+            Self::ArbitrumOne => Some("https://arbiscan.io"),
+            Self::Custom { .. } => None,
+        }
+    }
+
+    pub fn default_milkman_address(&self) -> Option<Address> {
+        match self {
+            Self::Mainnet => Some(MAINNET_MILKMAN_ADDRESS.parse().unwrap()),
+            _ => None,
+        }
+    }
+
+    pub fn default_hash_helper_address(&self) -> Option<Address> {
+        match self {
+            Self::Mainnet => Some(MAINNET_HASH_HELPER_ADDRESS.parse().unwrap()),
+            _ => None,
+        }
+    }
+
+    pub fn default_settlement_address(&self) -> Option<Address> {
+        match self {
+            Self::Custom { .. } => None,
+            _ => Some(SETTLEMENT_CONTRACT_ADDRESS.parse().unwrap()),
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_network_from_name() {
+        assert_eq!(
+            Network::from_name("mainnet", None).unwrap(),
+            Network::Mainnet
+        );
+        assert_eq!(
+            Network::from_name("xdai", Some(100)).unwrap(),
+            Network::GnosisChain
+        );
+        assert!(Network::from_name("sepolia", Some(1)).is_err());
+        assert!(Network::from_name("goerli", None).is_err());
+
+        let custom = Network::from_name("devnet", Some(31337)).unwrap();
+        assert_eq!(custom.chain_id(), 31337);
+        assert_eq!(custom.name(), "devnet");
+        assert_eq!(custom.cow_api_base_url(), None);
+        assert_eq!(custom.default_milkman_address(), None);
+        assert_eq!(custom.default_settlement_address(), None);
+        assert!(Network::ArbitrumOne.default_settlement_address().is_some());
+    }
+}
@@ -0,0 +1,119 @@
+use std::{collections::HashMap, env, fs, time::Duration, time::SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+use crate::configuration::{BotConfiguration, Configuration, CONFIG_FILE_VARIABLE};
+
+const FILE_POLLING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Re-read the configuration whenever the process gets a SIGHUP or the
+/// configuration file changes, and hand each network's loop its new
+/// configuration. Settings that can't change while running are logged and
+/// left as they were; a configuration that doesn't parse or validate is
+/// logged and ignored.
+pub async fn watch_configuration(
+    mut config: BotConfiguration,
+    senders: HashMap<String, watch::Sender<Configuration>>,
+) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => Some(hangups),
+        Err(err) => {
+            tracing::warn!("unable to listen for SIGHUP – {:?}", err);
+            None
+        }
+    };
+    let mut modified = config_file_modified();
+
+    loop {
+        tokio::select! {
+            Some(()) = async { hangups.as_mut()?.recv().await } => {
+                tracing::info!("Received SIGHUP, reloading configuration.");
+            }
+            _ = tokio::time::sleep(FILE_POLLING_INTERVAL) => {
+                let last_modified = config_file_modified();
+                if last_modified == modified {
+                    continue;
+                }
+                modified = last_modified;
+                tracing::info!("Configuration file changed, reloading configuration.");
+            }
+        }
+
+        match BotConfiguration::get_from_environment() {
+            Ok(reloaded) => reload(&mut config, reloaded, &senders),
+            Err(err) => {
+                tracing::error!(
+                    "unable to reload configuration, keeping the current one – {:?}",
+                    err
+                );
+            }
+        }
+    }
+}
+
+fn reload(
+    config: &mut BotConfiguration,
+    reloaded: BotConfiguration,
+    senders: &HashMap<String, watch::Sender<Configuration>>,
+) {
+    if reloaded.metrics_port != config.metrics_port {
+        tracing::warn!("`metrics_port` changed, restart the bot to apply it");
+    }
+    if reloaded.state_dir != config.state_dir {
+        tracing::warn!("`state_dir` changed, restart the bot to apply it");
+    }
+    if reloaded.shutdown_timeout_secs != config.shutdown_timeout_secs {
+        tracing::warn!("`shutdown_timeout_secs` changed, restart the bot to apply it");
+    }
+    if reloaded.exit_on_give_up != config.exit_on_give_up {
+        tracing::warn!("`exit_on_give_up` changed, restart the bot to apply it");
+    }
+
+    for network_config in &reloaded.networks {
+        if !senders.contains_key(&network_config.network.to_string()) {
+            tracing::warn!(
+                "network {} was added, restart the bot to run it",
+                network_config.network
+            );
+        }
+    }
+
+    for current in &mut config.networks {
+        let name = current.network.to_string();
+        let reloaded = match reloaded
+            .networks
+            .iter()
+            .find(|network_config| network_config.network.to_string() == name)
+        {
+            Some(reloaded) => reloaded.clone(),
+            None => {
+                tracing::warn!("network {} was removed, restart the bot to stop it", name);
+                continue;
+            }
+        };
+
+        let reload = current.reload(reloaded);
+        if !reload.needs_restart.is_empty() {
+            tracing::warn!(
+                "{}: restart the bot to apply {}",
+                name,
+                reload.needs_restart.join(", ")
+            );
+        }
+        if reload.applied.is_empty() {
+            continue;
+        }
+        tracing::info!("{}: applying {}", name, reload.applied.join(", "));
+        if let Some(sender) = senders.get(&name) {
+            // the network's loop may be restarting, it picks the value up after
+            let _ = sender.send(current.clone());
+        }
+    }
+}
+
+fn config_file_modified() -> Option<SystemTime> {
+    let path = env::var(CONFIG_FILE_VARIABLE).ok()?;
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
@@ -0,0 +1,230 @@
+use anyhow::{anyhow, bail, Result};
+use ethers::types::Address;
+use std::fmt;
+
+use crate::configuration::Configuration;
+use crate::cow_api_client::CowAPIClient;
+use crate::ethereum_client::{self, EthereumClient};
+use crate::network::Network;
+
+/// The outcome of checking a configuration against the chain and the CoW API.
+/// A typo in an address otherwise only shows up once swaps start failing.
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    checks: Vec<Check>,
+}
+
+#[derive(Debug)]
+struct Check {
+    name: String,
+    outcome: Result<String>,
+    mismatch: bool, // failed because of the configuration, rather than a node or API that's down
+}
+
+impl CheckReport {
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.outcome.is_ok())
+    }
+
+    /// Whether a check failed in a way that retrying won't fix, such as a
+    /// wrong chain ID or an address without contract code.
+    pub fn has_mismatches(&self) -> bool {
+        self.checks
+            .iter()
+            .any(|check| check.outcome.is_err() && check.mismatch)
+    }
+
+    /// Record a check that only fails if something can't be reached.
+    fn record(&mut self, name: impl Into<String>, outcome: Result<String>) {
+        self.record_check(name, outcome.map_err(CheckError::Unreachable));
+    }
+
+    fn record_check(&mut self, name: impl Into<String>, outcome: Result<String, CheckError>) {
+        let (outcome, mismatch) = match outcome {
+            Ok(detail) => (Ok(detail), false),
+            Err(CheckError::Mismatch(err)) => (Err(err), true),
+            Err(CheckError::Unreachable(err)) => (Err(err), false),
+        };
+        self.checks.push(Check {
+            name: name.into(),
+            outcome,
+            mismatch,
+        });
+    }
+}
+
+/// Why a check failed.
+#[derive(Debug)]
+enum CheckError {
+    Mismatch(anyhow::Error),    // the configuration doesn't match the chain
+    Unreachable(anyhow::Error), // the node or the API couldn't be asked
+}
+
+impl CheckError {
+    /// A contract that reverts a call isn't the contract it's configured as,
+    /// while any other failure to call it is the node's.
+    fn from_call(err: anyhow::Error) -> Self {
+        if ethereum_client::is_contract_revert(&err) {
+            Self::Mismatch(err)
+        } else {
+            Self::Unreachable(err)
+        }
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            match &check.outcome {
+                Ok(detail) => writeln!(f, "[PASS] {} – {}", check.name, detail)?,
+                Err(err) if check.mismatch => writeln!(f, "[FAIL] {} – {:#}", check.name, err)?,
+                Err(err) => writeln!(f, "[DOWN] {} – {:#}", check.name, err)?,
+            }
+        }
+        write!(
+            f,
+            "{}",
+            if self.passed() {
+                "All checks passed."
+            } else {
+                "Some checks failed."
+            }
+        )
+    }
+}
+
+pub async fn check_configuration(config: &Configuration) -> CheckReport {
+    let mut report = CheckReport::default();
+
+    let eth_client = match EthereumClient::new(config) {
+        Ok(eth_client) => eth_client,
+        Err(err) => {
+            report.record_check("create Ethereum client", Err(CheckError::Mismatch(err)));
+            return report;
+        }
+    };
+
+    let chain_id = match eth_client.get_chain_id().await {
+        Ok(chain_id) => {
+            report.record(
+                "connect to RPC",
+                Ok(format!("connected to chain {}", chain_id)),
+            );
+            chain_id
+        }
+        Err(err) => {
+            report.record("connect to RPC", Err(err));
+            // every other on-chain check would fail for the same reason
+            report.record(
+                "reach CoW API",
+                check_cow_api(&CowAPIClient::new(config)).await,
+            );
+            return report;
+        }
+    };
+    report.record_check(
+        "chain ID matches network",
+        check_chain_id(&config.network, chain_id).map_err(CheckError::Mismatch),
+    );
+
+    for deployment in &config.milkman_deployments {
+        report.record_check(
+            format!("Milkman {:#x} contract code", deployment.address),
+            check_code(&eth_client, deployment.address).await,
+        );
+        report.record_check(
+            format!("Milkman {:#x} DOMAIN_SEPARATOR", deployment.address),
+            eth_client
+                .get_domain_separator(deployment.address)
+                .await
+                .map(|domain_separator| {
+                    format!(
+                        "0x{}, version {}",
+                        hex::encode(domain_separator),
+                        deployment.version
+                    )
+                })
+                .map_err(CheckError::from_call),
+        );
+    }
+    report.record_check(
+        "GPv2Settlement contract code",
+        check_code(&eth_client, config.settlement_address).await,
+    );
+    report.record_check(
+        "HashHelper contract code",
+        check_code(&eth_client, config.hash_helper_address).await,
+    );
+    match config.state_helper_address {
+        Some(state_helper_address) => report.record_check(
+            "state helper contract code",
+            check_code(&eth_client, state_helper_address).await,
+        ),
+        None => report.record(
+            "state helper contract code",
+            Ok("skipped, no state helper configured".to_string()),
+        ),
+    }
+
+    report.record(
+        "reach CoW API",
+        check_cow_api(&CowAPIClient::new(config)).await,
+    );
+
+    report
+}
+
+fn check_chain_id(network: &Network, chain_id: u64) -> Result<String> {
+    if chain_id != network.chain_id() {
+        bail!(
+            "network `{}` has chain ID {} but the node is on chain {}",
+            network,
+            network.chain_id(),
+            chain_id
+        );
+    }
+    Ok(format!("chain {} is {}", chain_id, network))
+}
+
+async fn check_code(eth_client: &EthereumClient, address: Address) -> Result<String, CheckError> {
+    match eth_client.has_code(address).await {
+        Ok(true) => Ok(format!("code found at {:#x}", address)),
+        Ok(false) => Err(CheckError::Mismatch(anyhow!(
+            "no contract code at {:#x}",
+            address
+        ))),
+        Err(err) => Err(CheckError::Unreachable(err)),
+    }
+}
+
+async fn check_cow_api(cow_api_client: &CowAPIClient) -> Result<String> {
+    let version = cow_api_client.get_version().await?;
+    Ok(format!(
+        "{} is up, version {}",
+        cow_api_client.base_url,
+        version.trim().trim_matches('"')
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mismatches() {
+        let mut report = CheckReport::default();
+        report.record("reach CoW API", Err(anyhow!("connection refused")));
+        assert!(!report.passed());
+        assert!(!report.has_mismatches());
+
+        report.record_check(
+            "chain ID matches network",
+            check_chain_id(&Network::Mainnet, 100).map_err(CheckError::Mismatch),
+        );
+        assert!(report.has_mismatches());
+        assert!(report.to_string().contains("[DOWN] reach CoW API"));
+        assert!(report
+            .to_string()
+            .contains("[FAIL] chain ID matches network"));
+    }
+}
@@ -0,0 +1,57 @@
+use std::future::Future;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+/// Whether the bot has been asked to stop, by SIGTERM (what Kubernetes sends),
+/// Ctrl-C, or a network giving up on its node. Each network's loop holds a
+/// clone and stops starting new work once a shutdown is requested.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    pub fn listen() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        let sender = Arc::new(sender);
+        let shutdown = Self { sender, receiver };
+
+        let sender = Arc::clone(&shutdown.sender);
+        tokio::spawn(async move {
+            let mut terminations =
+                signal(SignalKind::terminate()).expect("Unable to listen for SIGTERM.");
+            tokio::select! {
+                _ = terminations.recv() => tracing::info!("Received SIGTERM, shutting down."),
+                _ = tokio::signal::ctrl_c() => tracing::info!("Received Ctrl-C, shutting down."),
+            }
+            let _ = sender.send(true);
+        });
+        shutdown
+    }
+
+    pub fn request(&self) {
+        let _ = self.sender.send(true);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Wait until a shutdown is requested.
+    pub async fn requested(&mut self) {
+        while !*self.receiver.borrow_and_update() {
+            // can't fail, as `self` holds a sender
+            let _ = self.receiver.changed().await;
+        }
+    }
+
+    /// Run `future` to completion, unless a shutdown is requested first.
+    pub async fn unless_requested<F: Future>(&mut self, future: F) -> Option<F::Output> {
+        tokio::select! {
+            output = future => Some(output),
+            _ = self.requested() => None,
+        }
+    }
+}
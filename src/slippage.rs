@@ -0,0 +1,173 @@
+use ethers::types::Address;
+use serde::Deserialize;
+use std::fmt;
+
+use crate::configuration::Configuration;
+use crate::types::Swap;
+
+/// A rule giving the slippage tolerance for the swaps it matches. A swap
+/// matches if it matches every condition that is set; conditions that are left
+/// out match any swap, so a rule without conditions matches every swap.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SlippageRule {
+    pub name: Option<String>,
+    pub from_token: Option<Address>,
+    pub to_token: Option<Address>,
+    pub token: Option<Address>, // either side of the swap
+    pub price_checker: Option<Address>,
+    pub order_creator: Option<Address>,
+    pub slippage_tolerance_bps: u16,
+}
+
+impl SlippageRule {
+    fn matches(&self, swap: &Swap) -> bool {
+        fn matches(condition: Option<Address>, value: Address) -> bool {
+            condition.is_none() || condition == Some(value)
+        }
+
+        matches(self.from_token, swap.from_token)
+            && matches(self.to_token, swap.to_token)
+            && (matches(self.token, swap.from_token) || matches(self.token, swap.to_token))
+            && matches(self.price_checker, swap.price_checker)
+            && matches(self.order_creator, swap.order_creator)
+    }
+}
+
+/// The slippage tolerance for a swap, and where it came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Slippage<'a> {
+    pub tolerance_bps: u16,
+    pub rule: Option<(usize, &'a SlippageRule)>, // `None` for `slippage_tolerance_bps`
+}
+
+impl fmt::Display for Slippage<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bps, from ", self.tolerance_bps)?;
+        match self.rule {
+            Some((
+                _,
+                SlippageRule {
+                    name: Some(name), ..
+                },
+            )) => write!(f, "rule `{}`", name),
+            Some((index, _)) => write!(f, "slippage rule #{}", index + 1),
+            None => write!(f, "`slippage_tolerance_bps`"),
+        }
+    }
+}
+
+/// Raise a swap's slippage tolerance by `slippage_step_bps` for each of its
+/// orders that expired without being filled, up to `max_slippage_bps`. A
+/// tolerance that's already above the ceiling is left as it is.
+pub fn escalate(tolerance_bps: u16, unfilled_orders: u32, config: &Configuration) -> u16 {
+    escalate_by(
+        tolerance_bps,
+        unfilled_orders,
+        config.slippage_step_bps,
+        config.max_slippage_bps,
+    )
+}
+
+fn escalate_by(tolerance_bps: u16, unfilled_orders: u32, step_bps: u16, max_bps: u16) -> u16 {
+    let escalated = u32::from(step_bps)
+        .saturating_mul(unfilled_orders)
+        .saturating_add(u32::from(tolerance_bps))
+        .min(u32::from(max_bps)) as u16;
+    escalated.max(tolerance_bps)
+}
+
+/// The slippage tolerance for a swap: that of the first of `slippage_rules`
+/// that matches it, or `slippage_tolerance_bps` if none does.
+pub fn slippage_for<'a>(config: &'a Configuration, swap: &Swap) -> Slippage<'a> {
+    resolve(&config.slippage_rules, config.slippage_tolerance_bps, swap)
+}
+
+fn resolve<'a>(rules: &'a [SlippageRule], default_bps: u16, swap: &Swap) -> Slippage<'a> {
+    match rules
+        .iter()
+        .enumerate()
+        .find(|(_, rule)| rule.matches(swap))
+    {
+        Some((index, rule)) => Slippage {
+            tolerance_bps: rule.slippage_tolerance_bps,
+            rule: Some((index, rule)),
+        },
+        None => Slippage {
+            tolerance_bps: default_bps,
+            rule: None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::U256;
+
+    fn rule(slippage_tolerance_bps: u16) -> SlippageRule {
+        SlippageRule {
+            name: None,
+            from_token: None,
+            to_token: None,
+            token: None,
+            price_checker: None,
+            order_creator: None,
+            slippage_tolerance_bps,
+        }
+    }
+
+    #[test]
+    fn test_slippage_for() {
+        let (dai, usdc, weth) = (Address::random(), Address::random(), Address::random());
+        let rules = vec![
+            SlippageRule {
+                name: Some("stables".to_string()),
+                from_token: Some(dai),
+                to_token: Some(usdc),
+                ..rule(5)
+            },
+            SlippageRule {
+                token: Some(weth),
+                ..rule(30)
+            },
+        ];
+        let swap = |from_token, to_token| Swap {
+            milkman: Address::random().into(),
+            order_contract: Address::random(),
+            order_creator: Address::random(),
+            receiver: Address::random(),
+            from_token,
+            to_token,
+            amount_in: U256::exp10(18),
+            price_checker: Address::random(),
+            price_checker_data: Default::default(),
+            provenance: None,
+        };
+
+        let slippage = resolve(&rules, 50, &swap(dai, usdc));
+        assert_eq!(slippage.tolerance_bps, 5);
+        assert_eq!(slippage.to_string(), "5 bps, from rule `stables`");
+
+        let slippage = resolve(&rules, 50, &swap(usdc, weth));
+        assert_eq!(slippage.tolerance_bps, 30);
+        assert_eq!(slippage.to_string(), "30 bps, from slippage rule #2");
+
+        let slippage = resolve(&rules, 50, &swap(usdc, dai));
+        assert_eq!(slippage.tolerance_bps, 50);
+        assert_eq!(
+            slippage.to_string(),
+            "50 bps, from `slippage_tolerance_bps`"
+        );
+    }
+
+    #[test]
+    fn test_escalate() {
+        assert_eq!(escalate_by(50, 0, 25, 200), 50);
+        assert_eq!(escalate_by(50, 2, 25, 200), 100);
+        assert_eq!(escalate_by(50, 100, 25, 200), 200);
+        assert_eq!(escalate_by(50, u32::MAX, u16::MAX, 200), 200);
+        assert_eq!(escalate_by(300, 2, 25, 200), 300);
+        assert_eq!(escalate_by(50, 3, 0, 200), 50);
+    }
+}
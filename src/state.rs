@@ -0,0 +1,164 @@
+use anyhow::{Context, Result};
+use ethers::types::Address;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::network::Network;
+use crate::swap_record::SwapRecord;
+use crate::types::BlockNumber;
+
+/// What a network's loop keeps between iterations. It lives outside the loop
+/// so that, if the loop panics, its replacement picks up where it left off,
+/// and it can be saved so that a restarted bot does too.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct NetworkState {
+    pub range_start: Option<BlockNumber>, // the block checkpoint
+    pub swap_queue: HashMap<Address, SwapRecord>,
+    #[serde(default)]
+    pub finished_swaps: HashMap<Address, BlockNumber>, // order contracts, and the block they finished at
+}
+
+impl NetworkState {
+    /// Load the state saved at `path`, or an empty state if nothing was saved.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => {
+                return Err(err).with_context(|| format!("unable to read `{}`", path.display()))
+            }
+        };
+        let mut state: Self = serde_json::from_str(&contents)
+            .with_context(|| format!("invalid network state in `{}`", path.display()))?;
+        state.skip_quoting();
+        Ok(state)
+    }
+
+    /// Put every swap that's being quoted back in the state it was in before.
+    /// No swap is being quoted between iterations, unless a loop panicked or
+    /// the bot was killed in the middle of one, which would leave the swap
+    /// stuck, as it can't start quoting again.
+    pub fn skip_quoting(&mut self) {
+        for record in self.swap_queue.values_mut() {
+            record.skip_quoting();
+        }
+    }
+
+    /// Remove a filled or cancelled swap from the queue, remembering it so
+    /// that discovery doesn't queue it again while its request is still in the
+    /// blocks being scanned.
+    pub fn finish(&mut self, order_contract: Address, block_number: BlockNumber) {
+        self.swap_queue.remove(&order_contract);
+        self.finished_swaps.insert(order_contract, block_number);
+    }
+
+    /// Forget the swaps that finished before `block_number`, whose requests
+    /// can't be in any scan starting from it.
+    pub fn forget_finished_before(&mut self, block_number: BlockNumber) {
+        self.finished_swaps
+            .retain(|_, finished_at| *finished_at >= block_number);
+    }
+
+    /// Write the state to a temporary file first, so that a bot killed while
+    /// saving leaves the previous state behind rather than half of the new one.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let temporary_path = path.with_extension("json.tmp");
+        fs::write(&temporary_path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("unable to write `{}`", temporary_path.display()))?;
+        fs::rename(&temporary_path, path)
+            .with_context(|| format!("unable to write `{}`", path.display()))
+    }
+}
+
+pub fn state_file(state_dir: &Path, network: &Network) -> PathBuf {
+    state_dir.join(format!("{}.json", network))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::Configuration;
+    use crate::swap_record::{Retry, SwapState};
+    use crate::types::{Swap, SwapProvenance};
+    use ethers::types::{H256, U256};
+
+    #[test]
+    fn test_save_and_load_state() {
+        let state_dir = std::env::temp_dir().join(format!("milkman-bot-{}", Address::random()));
+        fs::create_dir_all(&state_dir).unwrap();
+        let path = state_file(&state_dir, &Network::Mainnet);
+
+        assert_eq!(NetworkState::load(&path).unwrap(), NetworkState::default());
+
+        let swap = Swap {
+            milkman: Address::random().into(),
+            order_contract: Address::random(),
+            order_creator: Address::random(),
+            receiver: Address::random(),
+            from_token: Address::random(),
+            to_token: Address::random(),
+            amount_in: U256::exp10(18),
+            price_checker: Address::random(),
+            price_checker_data: vec![1, 2, 3].into(),
+            provenance: Some(SwapProvenance {
+                transaction_hash: H256::random(),
+                block_number: 16124151,
+                block_hash: H256::random(),
+                log_index: 3,
+                block_timestamp: 1670000000,
+            }),
+        };
+        let state = NetworkState {
+            range_start: Some(16124151),
+            swap_queue: HashMap::from([(swap.order_contract, SwapRecord::new(swap, 1670000000))]),
+            finished_swaps: HashMap::from([(Address::random(), 16124100)]),
+        };
+        state.save(&path).unwrap();
+        assert_eq!(NetworkState::load(&path).unwrap(), state);
+
+        // a swap left being quoted, by a panicked loop or a killed bot, goes
+        // back to where it was, whether the state is reused or loaded
+        let mut interrupted = state;
+        let record = interrupted.swap_queue.values_mut().next().unwrap();
+        record.start_quoting(1670000000).unwrap();
+        record
+            .failed(
+                "no liquidity".to_string(),
+                Retry::BackOff,
+                1670000000,
+                &Configuration::for_tests(),
+            )
+            .unwrap();
+        record.start_quoting(1670000060).unwrap();
+        interrupted.save(&path).unwrap();
+
+        let loaded = NetworkState::load(&path).unwrap();
+        let record = loaded.swap_queue.values().next().unwrap();
+        assert_eq!(record.state, SwapState::Failing);
+        assert_eq!(record.quoting_from, None);
+
+        interrupted.skip_quoting();
+        assert_eq!(interrupted, loaded);
+        let record = interrupted.swap_queue.values_mut().next().unwrap();
+        assert!(record.start_quoting(1670000120).is_ok());
+
+        fs::remove_dir_all(&state_dir).unwrap();
+    }
+
+    #[test]
+    fn test_finished_swaps() {
+        let (filled, cancelled) = (Address::random(), Address::random());
+        let mut state = NetworkState::default();
+        state.finish(filled, 100);
+        state.finish(cancelled, 150);
+        assert!(state.finished_swaps.contains_key(&filled));
+
+        state.forget_finished_before(100);
+        assert_eq!(state.finished_swaps.len(), 2);
+        state.forget_finished_before(101);
+        assert_eq!(state.finished_swaps, HashMap::from([(cancelled, 150)]));
+    }
+}
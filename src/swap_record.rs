@@ -0,0 +1,607 @@
+use anyhow::{bail, Result};
+use ethers::types::U256;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::app_data::OrderClass;
+use crate::configuration::Configuration;
+use crate::types::Swap;
+
+/// How long before its order expires a swap is handled again, so that a new
+/// order is up before the old one goes. Milkman's `isValidSignature` rejects
+/// orders that are valid for less than another 5 minutes (`expires_too_soon`),
+/// so an order can't be settled for its last 300s, and the margin is longer.
+const ORDER_REFRESH_MARGIN_SECS: u64 = 360;
+
+/// Where a swap is in its lifecycle.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SwapState {
+    #[default]
+    Discovered,
+    Quoting, // being handled in the current iteration
+    OrderPosted,
+    Filled,
+    Cancelled,
+    Failing,
+    Parked,           // failing for long enough that it's only rechecked now and then
+    FeeExceedsAmount, // the quoted fee takes too much of the amount, until gas is cheaper
+}
+
+impl SwapState {
+    pub const ALL: [SwapState; 8] = [
+        Self::Discovered,
+        Self::Quoting,
+        Self::OrderPosted,
+        Self::Filled,
+        Self::Cancelled,
+        Self::Failing,
+        Self::Parked,
+        Self::FeeExceedsAmount,
+    ];
+
+    fn can_become(self, next: SwapState) -> bool {
+        use SwapState::*;
+        matches!(
+            (self, next),
+            (
+                Discovered | OrderPosted | Failing | Parked | FeeExceedsAmount,
+                Quoting
+            ) | (
+                Quoting,
+                OrderPosted | Failing | Parked | FeeExceedsAmount | Filled | Cancelled
+            )
+        )
+    }
+
+    pub fn is_final(self) -> bool {
+        matches!(self, Self::Filled | Self::Cancelled)
+    }
+}
+
+impl fmt::Display for SwapState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            Self::Discovered => "discovered",
+            Self::Quoting => "quoting",
+            Self::OrderPosted => "order_posted",
+            Self::Filled => "filled",
+            Self::Cancelled => "cancelled",
+            Self::Failing => "failing",
+            Self::Parked => "parked",
+            Self::FeeExceedsAmount => "fee_exceeds_amount",
+        };
+        f.write_str(state)
+    }
+}
+
+/// How to go on with a swap that failed to be handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retry {
+    Soon,      // on the next tick, e.g. after a transient orderbook error
+    Throttled, // after `max_backoff_secs`, without counting towards parking, e.g. when rate limited
+    BackOff,   // exponentially, parking the swap if it keeps failing
+    Park,      // only on a recheck or a balance change, as it won't work before then
+}
+
+/// A queued swap, its lifecycle, and when it's next due to be handled. Fresh
+/// swaps are due immediately, swaps with a live order just before the order
+/// expires, and swaps that keep failing back off exponentially. Swaps that
+/// fail `park_after_failures` times in a row, or fail when they're older than
+/// `park_after_secs`, are parked and only rechecked every `parked_recheck_secs`,
+/// until an order goes through or their balance changes. Each iteration of a
+/// network's loop only touches the swaps that are due.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SwapRecord {
+    #[serde(flatten)]
+    pub swap: Swap,
+    #[serde(default)]
+    pub state: SwapState,
+    #[serde(default)]
+    pub discovered_at: u64, // unix timestamps, in seconds
+    #[serde(default)]
+    pub updated_at: u64,
+    #[serde(default)]
+    pub next_action_at: u64,
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default)]
+    pub consecutive_failures: u32,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    #[serde(default)]
+    pub order_uids: Vec<String>, // every order posted for the swap, oldest first
+    #[serde(default)]
+    pub market_orders_valid_to: Vec<u64>, // when each market order posted for the swap expires
+    #[serde(default)]
+    pub parked_at: Option<u64>,
+    #[serde(default)]
+    pub balance: Option<U256>, // of `from_token`, when it was last checked
+    #[serde(default)]
+    pub slippage_bps: Option<u16>, // of the latest order, escalated as orders go unfilled
+    #[serde(default)]
+    pub quoting_from: Option<SwapState>, // the state before `Quoting`, while quoting
+}
+
+impl SwapRecord {
+    pub fn new(swap: Swap, now: u64) -> Self {
+        Self {
+            swap,
+            state: SwapState::Discovered,
+            discovered_at: now,
+            updated_at: now,
+            next_action_at: now,
+            attempts: 0,
+            consecutive_failures: 0,
+            last_error: None,
+            order_uids: Vec::new(),
+            market_orders_valid_to: Vec::new(),
+            parked_at: None,
+            balance: None,
+            slippage_bps: None,
+            quoting_from: None,
+        }
+    }
+
+    /// Market orders posted for the swap that expired without being filled, as
+    /// the swap is still open. Limit orders, which rest until the market
+    /// reaches them, and orders that are still live don't count.
+    pub fn unfilled_orders(&self, now: u64) -> u32 {
+        self.market_orders_valid_to
+            .iter()
+            .filter(|valid_to| **valid_to <= now)
+            .count() as u32
+    }
+
+    /// Seconds since the swap was requested, or since we found it if we don't
+    /// know when it was requested.
+    pub fn age(&self, now: u64) -> u64 {
+        let requested_at = match self.swap.provenance {
+            Some(provenance) => provenance.block_timestamp,
+            None => self.discovered_at,
+        };
+        now.saturating_sub(requested_at)
+    }
+
+    /// Record the swap's current balance, unparking it, and making it due, if
+    /// the balance changed since it was last checked. Returns whether it was
+    /// unparked.
+    pub fn balance_checked(&mut self, balance: U256, now: u64) -> bool {
+        let changed = matches!(self.balance, Some(previous) if previous != balance);
+        self.balance = Some(balance);
+        if !(changed && self.unpark()) {
+            return false;
+        }
+        self.next_action_at = self.next_action_at.min(now);
+        true
+    }
+
+    fn unpark(&mut self) -> bool {
+        self.consecutive_failures = 0;
+        self.parked_at.take().is_some()
+    }
+
+    pub fn is_due(&self, now: u64) -> bool {
+        self.next_action_at <= now
+    }
+
+    fn transition(&mut self, next: SwapState, now: u64) -> Result<()> {
+        if !self.state.can_become(next) {
+            bail!(
+                "swap with order contract {:#x} can't go from {} to {}",
+                self.swap.order_contract,
+                self.state,
+                next
+            );
+        }
+        self.quoting_from = (next == SwapState::Quoting).then_some(self.state);
+        self.state = next;
+        self.updated_at = now;
+        Ok(())
+    }
+
+    /// Start handling the swap.
+    pub fn start_quoting(&mut self, now: u64) -> Result<()> {
+        self.transition(SwapState::Quoting, now)
+    }
+
+    /// Go back to the state the swap was in before `start_quoting`, without
+    /// counting an attempt, if it's still being quoted. That's the case for
+    /// swaps that weren't handled after all, and for swaps whose loop panicked
+    /// or whose bot was killed while handling them.
+    pub fn skip_quoting(&mut self) {
+        if self.state == SwapState::Quoting {
+            self.state = self.quoting_from.take().unwrap_or_default();
+        }
+    }
+
+    /// An order was posted for the swap. Its UID is unknown if the orderbook
+    /// already had it, in which case it was recorded when it was first posted.
+    pub fn order_posted(
+        &mut self,
+        order_uid: Option<String>,
+        order_class: OrderClass,
+        valid_to: u64,
+        slippage_bps: Option<u16>,
+        now: u64,
+        config: &Configuration,
+    ) -> Result<()> {
+        self.transition(SwapState::OrderPosted, now)?;
+        self.attempts += 1;
+        self.unpark();
+        self.last_error = None;
+        if order_class == OrderClass::Market && !self.market_orders_valid_to.contains(&valid_to) {
+            self.market_orders_valid_to.push(valid_to);
+        }
+        self.order_uids.extend(order_uid);
+        self.slippage_bps = slippage_bps;
+        self.next_action_at = valid_to
+            .saturating_sub(ORDER_REFRESH_MARGIN_SECS)
+            .max(now + config.polling_frequency_secs);
+        Ok(())
+    }
+
+    /// Retry a failing swap as `retry` says. Backing off waits the polling
+    /// frequency, doubled for every consecutive failure, up to
+    /// `max_backoff_secs`, and parks the swap if it has failed for long enough.
+    /// Retrying soon or after being throttled doesn't count towards parking,
+    /// nor does it unpark a parked swap, which stays on `parked_recheck_secs`.
+    pub fn failed(
+        &mut self,
+        error: String,
+        retry: Retry,
+        now: u64,
+        config: &Configuration,
+    ) -> Result<()> {
+        if matches!(retry, Retry::Soon | Retry::Throttled) {
+            let wait = match retry {
+                Retry::Throttled => config.max_backoff_secs.max(config.polling_frequency_secs),
+                _ => config.polling_frequency_secs,
+            };
+            if self.parked_at.is_some() {
+                self.transition(SwapState::Parked, now)?;
+                self.next_action_at = now + config.parked_recheck_secs.max(wait);
+            } else {
+                self.transition(SwapState::Failing, now)?;
+                self.next_action_at = now + wait;
+            }
+            self.attempts += 1;
+            self.last_error = Some(error);
+            return Ok(());
+        }
+
+        let consecutive_failures = self.consecutive_failures.saturating_add(1);
+        let park = retry == Retry::Park
+            || consecutive_failures >= config.park_after_failures
+            || matches!(config.park_after_secs, Some(park_after_secs) if self.age(now) >= park_after_secs);
+        if park {
+            self.transition(SwapState::Parked, now)?;
+            self.parked_at.get_or_insert(now);
+        } else {
+            self.transition(SwapState::Failing, now)?;
+        }
+        self.attempts += 1;
+        self.consecutive_failures = consecutive_failures;
+        self.last_error = Some(error);
+
+        if park {
+            self.next_action_at = now + config.parked_recheck_secs;
+            return Ok(());
+        }
+        self.next_action_at = now + backoff(self.consecutive_failures, config);
+        Ok(())
+    }
+
+    /// Defer a swap whose quoted fee is more than `max_fee_bps` of its amount,
+    /// requoting it every `fee_recheck_secs` until gas is cheaper. This isn't a
+    /// failure, so it doesn't count towards parking, and unparks the swap.
+    pub fn fee_exceeds_amount(
+        &mut self,
+        fee_amount: U256,
+        max_fee_bps: u16,
+        now: u64,
+        config: &Configuration,
+    ) -> Result<()> {
+        self.transition(SwapState::FeeExceedsAmount, now)?;
+        self.attempts += 1;
+        self.unpark();
+        self.last_error = Some(format!(
+            "fee of {} exceeds {} bps of the amount of {}",
+            fee_amount, max_fee_bps, self.swap.amount_in
+        ));
+        self.next_action_at = now + config.fee_recheck_secs.max(config.polling_frequency_secs);
+        Ok(())
+    }
+
+    pub fn finished(&mut self, filled: bool, now: u64) -> Result<()> {
+        let state = if filled {
+            SwapState::Filled
+        } else {
+            SwapState::Cancelled
+        };
+        self.transition(state, now)
+    }
+}
+
+/// How long to wait after `consecutive_failures` failures in a row: the polling
+/// frequency, doubled for every failure after the first, up to `max_backoff_secs`.
+fn backoff(consecutive_failures: u32, config: &Configuration) -> u64 {
+    config
+        .polling_frequency_secs
+        .saturating_mul(1 << consecutive_failures.saturating_sub(1).min(16))
+        .min(config.max_backoff_secs)
+        .max(config.polling_frequency_secs)
+}
+
+/// Whether a fee is at least the whole amount, so nothing would be left to
+/// sell, or more than `max_fee_bps` of it.
+pub fn fee_exceeds_limit(fee_amount: U256, amount: U256, max_fee_bps: u16) -> bool {
+    fee_amount >= amount
+        || fee_amount.full_mul(U256::from(10000)) > amount.full_mul(U256::from(max_fee_bps))
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before the unix epoch")
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::Address;
+
+    fn test_swap() -> Swap {
+        Swap {
+            milkman: Address::random().into(),
+            order_contract: Address::random(),
+            order_creator: Address::random(),
+            receiver: Address::random(),
+            from_token: Address::random(),
+            to_token: Address::random(),
+            amount_in: U256::exp10(18),
+            price_checker: Address::random(),
+            price_checker_data: Default::default(),
+            provenance: None,
+        }
+    }
+
+    #[test]
+    fn test_swap_lifecycle() {
+        let config = Configuration::for_tests();
+        let swap = test_swap();
+        let now = 1_000_000;
+
+        let mut record = SwapRecord::new(swap, now);
+        assert!(record.is_due(now));
+        assert!(record
+            .order_posted(
+                Some("0x01".to_string()),
+                OrderClass::Market,
+                now,
+                Some(50),
+                now,
+                &config
+            )
+            .is_err());
+
+        record.start_quoting(now).unwrap();
+        record.skip_quoting();
+        assert_eq!(record.state, SwapState::Discovered);
+
+        for _ in 0..4 {
+            record.start_quoting(now).unwrap();
+            record
+                .failed("no liquidity".to_string(), Retry::BackOff, now, &config)
+                .unwrap();
+        }
+        assert_eq!(record.state, SwapState::Failing);
+        assert_eq!(record.attempts, 4);
+        assert_eq!(record.next_action_at, now + 60);
+        assert!(!record.is_due(now + 59));
+
+        record.start_quoting(now).unwrap();
+        record
+            .order_posted(
+                Some("0x01".to_string()),
+                OrderClass::Market,
+                now + 1800,
+                Some(50),
+                now,
+                &config,
+            )
+            .unwrap();
+        assert_eq!(record.consecutive_failures, 0);
+        assert_eq!(record.last_error, None);
+        assert_eq!(record.order_uids, vec!["0x01".to_string()]);
+        assert_eq!(record.unfilled_orders(now + 1799), 0);
+        assert_eq!(record.unfilled_orders(now + 1800), 1);
+        assert_eq!(record.slippage_bps, Some(50));
+        assert_eq!(record.next_action_at, now + 1440);
+
+        // an order that's about to expire isn't re-handled before the next tick
+        record.start_quoting(now).unwrap();
+        record
+            .order_posted(
+                Some("0x02".to_string()),
+                OrderClass::Market,
+                now + 5,
+                Some(50),
+                now,
+                &config,
+            )
+            .unwrap();
+        assert_eq!(record.next_action_at, now + 10);
+
+        // the orderbook already had the order, so it isn't counted again, and
+        // limit orders don't count as unfilled
+        record.start_quoting(now).unwrap();
+        record
+            .order_posted(None, OrderClass::Market, now + 5, Some(50), now, &config)
+            .unwrap();
+        record.start_quoting(now).unwrap();
+        record
+            .order_posted(
+                Some("0x03".to_string()),
+                OrderClass::Limit,
+                now + 5,
+                None,
+                now,
+                &config,
+            )
+            .unwrap();
+        assert_eq!(record.order_uids.len(), 3);
+        assert_eq!(record.unfilled_orders(now + 86400), 2);
+
+        record.start_quoting(now).unwrap();
+        record.finished(true, now).unwrap();
+        assert_eq!(record.state, SwapState::Filled);
+        assert!(record.start_quoting(now).is_err());
+    }
+
+    #[test]
+    fn test_fee_exceeds_amount() {
+        let config = Configuration::for_tests();
+        let now = 1_000_000;
+
+        assert!(fee_exceeds_limit(U256::exp10(18), U256::exp10(18), 10000));
+        assert!(!fee_exceeds_limit(U256::exp10(17), U256::exp10(18), 10000));
+        assert!(fee_exceeds_limit(U256::exp10(17), U256::exp10(18), 500));
+        assert!(!fee_exceeds_limit(U256::MAX - 1, U256::MAX, 10000));
+
+        let mut record = SwapRecord::new(test_swap(), now);
+        record.start_quoting(now).unwrap();
+        record
+            .fee_exceeds_amount(U256::exp10(19), 10000, now, &config)
+            .unwrap();
+        assert_eq!(record.state, SwapState::FeeExceedsAmount);
+        assert_eq!(record.consecutive_failures, 0);
+        assert_eq!(record.next_action_at, now + 600);
+        record.start_quoting(now + 600).unwrap();
+    }
+
+    #[test]
+    fn test_park_swap() {
+        let mut config = Configuration::for_tests();
+        config.park_after_failures = 2;
+        config.park_after_secs = Some(86400);
+        let now = 1_000_000;
+
+        let mut record = SwapRecord::new(test_swap(), now);
+        record.balance_checked(U256::exp10(18), now);
+        for _ in 0..2 {
+            record.start_quoting(now).unwrap();
+            record
+                .failed("no liquidity".to_string(), Retry::BackOff, now, &config)
+                .unwrap();
+        }
+        assert_eq!(record.state, SwapState::Parked);
+        assert_eq!(record.parked_at, Some(now));
+        assert_eq!(record.next_action_at, now + 3600);
+
+        // still failing on a recheck, so still parked
+        record.start_quoting(now + 3600).unwrap();
+        assert!(!record.balance_checked(U256::exp10(18), now + 3600));
+        record
+            .failed(
+                "no liquidity".to_string(),
+                Retry::BackOff,
+                now + 3600,
+                &config,
+            )
+            .unwrap();
+        assert_eq!(record.state, SwapState::Parked);
+        assert_eq!(record.parked_at, Some(now));
+
+        // a balance change between rechecks unparks it and makes it due
+        assert!(record.balance_checked(U256::exp10(17), now + 3660));
+        assert!(record.is_due(now + 3660));
+        record.start_quoting(now + 3660).unwrap();
+        record
+            .failed(
+                "no liquidity".to_string(),
+                Retry::BackOff,
+                now + 3660,
+                &config,
+            )
+            .unwrap();
+        assert_eq!(record.state, SwapState::Failing);
+
+        // and so does a successful order
+        record.start_quoting(now + 7200).unwrap();
+        record
+            .failed(
+                "no liquidity".to_string(),
+                Retry::BackOff,
+                now + 7200,
+                &config,
+            )
+            .unwrap();
+        assert_eq!(record.state, SwapState::Parked);
+        record.start_quoting(now + 10800).unwrap();
+        record
+            .order_posted(
+                Some("0x01".to_string()),
+                OrderClass::Market,
+                now + 12600,
+                Some(50),
+                now + 10800,
+                &config,
+            )
+            .unwrap();
+        assert_eq!(record.parked_at, None);
+
+        // a swap older than `park_after_secs` is parked on its first failure
+        let mut record = SwapRecord::new(test_swap(), now);
+        record.start_quoting(now + 86400).unwrap();
+        record
+            .failed(
+                "no liquidity".to_string(),
+                Retry::BackOff,
+                now + 86400,
+                &config,
+            )
+            .unwrap();
+        assert_eq!(record.state, SwapState::Parked);
+
+        // transient errors don't count towards parking, errors that won't go
+        // away park the swap straight away
+        let mut record = SwapRecord::new(test_swap(), now);
+        for _ in 0..3 {
+            record.start_quoting(now).unwrap();
+            record
+                .failed("rate limited".to_string(), Retry::Soon, now, &config)
+                .unwrap();
+        }
+        assert_eq!(record.state, SwapState::Failing);
+        assert_eq!(record.consecutive_failures, 0);
+        assert_eq!(record.next_action_at, now + 10);
+        for _ in 0..30 {
+            record.start_quoting(now).unwrap();
+            record
+                .failed("rate limited".to_string(), Retry::Throttled, now, &config)
+                .unwrap();
+        }
+        assert_eq!(record.state, SwapState::Failing);
+        assert_eq!(record.consecutive_failures, 0);
+        assert_eq!(record.next_action_at, now + 60);
+        record.start_quoting(now).unwrap();
+        record
+            .failed("unsupported token".to_string(), Retry::Park, now, &config)
+            .unwrap();
+        assert_eq!(record.state, SwapState::Parked);
+
+        // a parked swap stays parked through transient errors
+        for retry in [Retry::Soon, Retry::Throttled] {
+            record.start_quoting(now + 3600).unwrap();
+            record
+                .failed("server error".to_string(), retry, now + 3600, &config)
+                .unwrap();
+            assert_eq!(record.state, SwapState::Parked);
+            assert_eq!(record.parked_at, Some(now));
+            assert_eq!(record.next_action_at, now + 7200);
+        }
+    }
+}
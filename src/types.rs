@@ -1,9 +1,13 @@
 use ethers::abi::Address;
-use ethers::types::{Bytes, U256};
+use ethers::types::{Bytes, H256, U256};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, PartialEq, Clone)]
+use crate::milkman_adapter::MilkmanDeployment;
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Swap {
-    pub order_contract: Address, // 1 swap per contract so this can be used as a UID
+    pub milkman: MilkmanDeployment, // the deployment the swap was requested from
+    pub order_contract: Address,    // 1 swap per contract so this can be used as a UID
     pub order_creator: Address,
     pub receiver: Address,
     pub from_token: Address,
@@ -11,6 +15,25 @@ pub struct Swap {
     pub amount_in: U256,
     pub price_checker: Address,
     pub price_checker_data: Bytes,
+    #[serde(default)]
+    pub provenance: Option<SwapProvenance>, // unknown until the request is mined
+}
+
+/// The log a swap was requested in.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct SwapProvenance {
+    pub transaction_hash: H256,
+    pub block_number: BlockNumber,
+    pub block_hash: H256,
+    pub log_index: u64,
+    pub block_timestamp: u64,
+}
+
+impl SwapProvenance {
+    /// Orders swaps by when they were requested.
+    pub fn position(&self) -> (BlockNumber, u64) {
+        (self.block_number, self.log_index)
+    }
 }
 
 pub type BlockNumber = u64;